@@ -0,0 +1,106 @@
+//! Data of a music box card, it is the same on all tag types
+
+/// Marks the data of a music box card
+const CARD_COOKIE: u32 = 0x1337B347;
+/// Version of the card layout that is written
+const CARD_VERSION: u8 = 0x02;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Card {
+    cookie: u32,
+    version: u8,
+    folder: u8,
+    mode: u8,
+    special1: u8,
+    special2: u8,
+}
+
+impl core::convert::TryFrom<[u8; 16]> for Card {
+    type Error = ();
+
+    fn try_from(value: [u8; 16]) -> Result<Self, Self::Error> {
+        // Check the cookie
+        let cookie = u32::from_be_bytes([value[0], value[1], value[2], value[3]]);
+        if cookie != CARD_COOKIE {
+            return Err(());
+        }
+
+        // Encode the rest
+        let version = value[4];
+        let folder = value[5];
+        let mode = value[6];
+        let special1 = value[7];
+        let special2 = value[8];
+
+        Ok(Self {
+            cookie,
+            version,
+            folder,
+            mode,
+            special1,
+            special2,
+        })
+    }
+}
+
+impl From<Card> for [u8; 16] {
+    fn from(card: Card) -> Self {
+        let cookie = card.cookie.to_be_bytes();
+        [
+            cookie[0],
+            cookie[1],
+            cookie[2],
+            cookie[3],
+            card.version,
+            card.folder,
+            card.mode,
+            card.special1,
+            card.special2,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ]
+    }
+}
+
+impl Card {
+    /// Cards written with a newer layout can not be understood
+    pub fn is_supported(&self) -> bool {
+        self.version <= CARD_VERSION
+    }
+
+    pub fn new(folder: u8, mode: u8, special1: u8, special2: u8) -> Self {
+        Self {
+            cookie: CARD_COOKIE,
+            version: CARD_VERSION,
+            folder,
+            mode,
+            special1,
+            special2,
+        }
+    }
+
+    /// Mode byte, it tells how the folder is played or which modifier the card is
+    pub fn mode(&self) -> u8 {
+        self.mode
+    }
+
+    /// Folder with the tracks, 0 for modifier cards
+    pub fn folder(&self) -> u8 {
+        self.folder
+    }
+
+    /// First parameter of the mode
+    pub fn special1(&self) -> u8 {
+        self.special1
+    }
+
+    /// Second parameter of the mode
+    pub fn special2(&self) -> u8 {
+        self.special2
+    }
+}
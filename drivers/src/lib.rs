@@ -0,0 +1,11 @@
+//! Drivers of the music box without any dependency on the board.
+//!
+//! The firmware connects them to the peripherals of the Blue Pill, the tests connect them to
+//! the emulators on the host.
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod card;
+pub mod player;
+pub mod prompts;
+pub mod tagreader;
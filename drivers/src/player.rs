@@ -0,0 +1,1346 @@
+use core::convert::TryFrom;
+use core::ops::Add;
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::serial::{Read, Write};
+
+/// Start byte of every frame
+const FRAME_START: u8 = 0x7E;
+/// Version byte of every frame
+const FRAME_VERSION: u8 = 0xFF;
+/// Length byte of every frame (counted without start, end and checksum)
+const FRAME_LEN: u8 = 0x06;
+/// End byte of every frame
+const FRAME_END: u8 = 0xEF;
+
+/// Size of a complete frame
+const FRAME_SIZE: usize = 10;
+
+/// Highest volume accepted by the DFPlayer
+pub const MAX_VOLUME: u8 = 30;
+
+/// Default time to wait for an answer
+const DEFAULT_TIMEOUT_MS: u32 = 200;
+
+/// Default minimum time between two frames
+const DEFAULT_GAP_MS: u32 = 50;
+
+/// Track finished notifications for the same track within this time are duplicates
+const DUPLICATE_WINDOW_MS: u32 = 500;
+
+/// Chips that are used on DFPlayer modules
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ChipVariant {
+    /// Original YX5200-24SS
+    Yx5200,
+    /// MH2024K-24SS clone
+    Mh2024k,
+    /// GD3200B clone
+    Gd3200b,
+}
+
+/// Differences in the behaviour of the chips
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Quirks {
+    /// Track finished notifications are send twice
+    pub duplicate_track_finished: bool,
+    /// Commands are acknowledged if requested
+    pub sends_ack: bool,
+    /// The module reports to be online (0x3F) after a reset
+    pub sends_online: bool,
+    /// Time the module needs to boot after a reset
+    pub boot_ms: u32,
+    /// Command byte of the answer to the folder track count query
+    pub folder_count_answer: u8,
+}
+
+impl ChipVariant {
+    pub fn quirks(&self) -> Quirks {
+        match self {
+            ChipVariant::Yx5200 => Quirks {
+                duplicate_track_finished: true,
+                sends_ack: true,
+                sends_online: true,
+                boot_ms: 1500,
+                folder_count_answer: 0x4E,
+            },
+            ChipVariant::Mh2024k => Quirks {
+                duplicate_track_finished: true,
+                sends_ack: false,
+                sends_online: true,
+                boot_ms: 3000,
+                folder_count_answer: 0x4E,
+            },
+            ChipVariant::Gd3200b => Quirks {
+                duplicate_track_finished: false,
+                sends_ack: true,
+                sends_online: false,
+                boot_ms: 1000,
+                folder_count_answer: 0x4F,
+            },
+        }
+    }
+}
+
+/// Commands that can be send to the DFPlayer
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Command {
+    /// Play the next track
+    Next,
+    /// Play the previous track
+    Previous,
+    /// Increase the volume by one step
+    VolumeUp,
+    /// Decrease the volume by one step
+    VolumeDown,
+    /// Set the volume (0..=30)
+    SetVolume(u8),
+    /// Enter standby mode
+    Standby,
+    /// Leave standby mode
+    Wake,
+    /// Reset the module
+    Reset,
+    /// Resume the paused track
+    Resume,
+    /// Pause the current track
+    Pause,
+    /// Play track (1..=255) from folder (1..=99)
+    PlayFolderTrack(u8, u8),
+    /// Play track from the `mp3` folder
+    PlayMp3(u16),
+    /// Interrupt the current track with a track from the `ADVERT` folder.
+    /// The interrupted track continues afterwards.
+    Advert(u16),
+    /// Select the equalizer preset
+    SetEqualizer(Equalizer),
+    /// Select the device the tracks are played from
+    SetPlaybackDevice(PlaybackDevice),
+}
+
+impl Command {
+    /// Command byte of the frame
+    pub fn code(&self) -> u8 {
+        match self {
+            Command::Next => 0x01,
+            Command::Previous => 0x02,
+            Command::VolumeUp => 0x04,
+            Command::VolumeDown => 0x05,
+            Command::SetVolume(_) => 0x06,
+            Command::Standby => 0x0A,
+            Command::Wake => 0x0B,
+            Command::Reset => 0x0C,
+            Command::Resume => 0x0D,
+            Command::Pause => 0x0E,
+            Command::PlayFolderTrack(_, _) => 0x0F,
+            Command::SetEqualizer(_) => 0x07,
+            Command::SetPlaybackDevice(_) => 0x09,
+            Command::PlayMp3(_) => 0x12,
+            Command::Advert(_) => 0x13,
+        }
+    }
+
+    /// Parameter bytes of the frame
+    pub fn param(&self) -> u16 {
+        match *self {
+            Command::SetVolume(volume) => u16::from(volume.min(MAX_VOLUME)),
+            Command::PlayFolderTrack(folder, track) => u16::from_be_bytes([folder, track]),
+            Command::PlayMp3(track) | Command::Advert(track) => track,
+            Command::SetEqualizer(equalizer) => u16::from(equalizer as u8),
+            Command::SetPlaybackDevice(device) => u16::from(device as u8),
+            _ => 0,
+        }
+    }
+
+    /// Build the frame for this command
+    pub fn frame(&self, ack: bool) -> [u8; FRAME_SIZE] {
+        frame(self.code(), ack, self.param())
+    }
+
+    /// The DFPlayer starts to play a track
+    pub fn starts_playback(&self) -> bool {
+        matches!(
+            self,
+            Command::Next
+                | Command::Previous
+                | Command::Resume
+                | Command::PlayFolderTrack(_, _)
+                | Command::PlayMp3(_)
+        )
+    }
+
+    /// The DFPlayer stops to play
+    pub fn stops_playback(&self) -> bool {
+        matches!(self, Command::Pause | Command::Standby | Command::Reset)
+    }
+}
+
+/// Equalizer presets of the DFPlayer
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Equalizer {
+    Normal = 0,
+    Pop = 1,
+    Rock = 2,
+    Jazz = 3,
+    Classic = 4,
+    Bass = 5,
+}
+
+impl Equalizer {
+    /// Next preset, starts again with the first one
+    pub fn next(self) -> Self {
+        Equalizer::try_from((self as u8 + 1) % 6).unwrap_or(Equalizer::Normal)
+    }
+
+    /// Previous preset, starts again with the last one
+    pub fn previous(self) -> Self {
+        Equalizer::try_from((self as u8 + 5) % 6).unwrap_or(Equalizer::Normal)
+    }
+}
+
+impl TryFrom<u8> for Equalizer {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Equalizer::Normal),
+            1 => Ok(Equalizer::Pop),
+            2 => Ok(Equalizer::Rock),
+            3 => Ok(Equalizer::Jazz),
+            4 => Ok(Equalizer::Classic),
+            5 => Ok(Equalizer::Bass),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Devices the DFPlayer can play from
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PlaybackDevice {
+    UsbDrive = 1,
+    SdCard = 2,
+    Flash = 5,
+}
+
+impl PlaybackDevice {
+    /// Next device, starts again with the first one
+    pub fn next(self) -> Self {
+        match self {
+            PlaybackDevice::UsbDrive => PlaybackDevice::SdCard,
+            PlaybackDevice::SdCard => PlaybackDevice::Flash,
+            PlaybackDevice::Flash => PlaybackDevice::UsbDrive,
+        }
+    }
+
+    /// Previous device, starts again with the last one
+    pub fn previous(self) -> Self {
+        match self {
+            PlaybackDevice::UsbDrive => PlaybackDevice::Flash,
+            PlaybackDevice::SdCard => PlaybackDevice::UsbDrive,
+            PlaybackDevice::Flash => PlaybackDevice::SdCard,
+        }
+    }
+}
+
+impl TryFrom<u8> for PlaybackDevice {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(PlaybackDevice::UsbDrive),
+            2 => Ok(PlaybackDevice::SdCard),
+            5 => Ok(PlaybackDevice::Flash),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Questions that can be asked to the DFPlayer
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Query {
+    /// Current status of the module
+    Status,
+    /// Current volume
+    Volume,
+    /// Number of the current track on the SD card
+    CurrentTrack,
+    /// Number of files on the SD card
+    FileCount,
+    /// Number of tracks in a folder
+    FolderTrackCount(u8),
+}
+
+impl Query {
+    /// Command byte of the query. The answer uses the same command byte
+    pub fn code(&self) -> u8 {
+        match self {
+            Query::Status => 0x42,
+            Query::Volume => 0x43,
+            Query::FileCount => 0x48,
+            Query::CurrentTrack => 0x4C,
+            Query::FolderTrackCount(_) => 0x4E,
+        }
+    }
+
+    /// Parameter bytes of the query
+    pub fn param(&self) -> u16 {
+        match *self {
+            Query::FolderTrackCount(folder) => u16::from(folder),
+            _ => 0,
+        }
+    }
+
+    /// Build the frame for this query
+    pub fn frame(&self) -> [u8; FRAME_SIZE] {
+        frame(self.code(), false, self.param())
+    }
+}
+
+/// Playback state reported by the status query
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PlayState {
+    Stopped,
+    Playing,
+    Paused,
+}
+
+impl TryFrom<u16> for PlayState {
+    type Error = ();
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        // The high byte contains the active media
+        match value & 0x00FF {
+            0x00 => Ok(PlayState::Stopped),
+            0x01 => Ok(PlayState::Playing),
+            0x02 => Ok(PlayState::Paused),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Checksum over version, length, command, ack and parameter bytes.
+/// It is the two's complement of the sum of these bytes.
+pub fn checksum(bytes: &[u8]) -> u16 {
+    bytes
+        .iter()
+        .fold(0_u16, |sum, byte| sum.wrapping_add(u16::from(*byte)))
+        .wrapping_neg()
+}
+
+/// Build a frame `7E FF 06 cmd ack p1 p2 chk_hi chk_lo EF`
+pub fn frame(cmd: u8, ack: bool, param: u16) -> [u8; FRAME_SIZE] {
+    let [param_hi, param_lo] = param.to_be_bytes();
+    let mut frame = [
+        FRAME_START,
+        FRAME_VERSION,
+        FRAME_LEN,
+        cmd,
+        ack as u8,
+        param_hi,
+        param_lo,
+        0x00,
+        0x00,
+        FRAME_END,
+    ];
+    let [checksum_hi, checksum_lo] = checksum(&frame[1..7]).to_be_bytes();
+    frame[7] = checksum_hi;
+    frame[8] = checksum_lo;
+    frame
+}
+
+/// A valid frame received from the DFPlayer
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Frame {
+    pub cmd: u8,
+    pub param: u16,
+}
+
+impl Frame {
+    /// Decode a complete frame. Returns None if the framing or the checksum is wrong
+    pub fn decode(bytes: &[u8; FRAME_SIZE]) -> Option<Self> {
+        if bytes[0] != FRAME_START
+            || bytes[1] != FRAME_VERSION
+            || bytes[2] != FRAME_LEN
+            || bytes[9] != FRAME_END
+        {
+            return None;
+        }
+
+        if checksum(&bytes[1..7]) != u16::from_be_bytes([bytes[7], bytes[8]]) {
+            return None;
+        }
+
+        Some(Self {
+            cmd: bytes[3],
+            param: u16::from_be_bytes([bytes[5], bytes[6]]),
+        })
+    }
+}
+
+/// Collects received bytes until a valid frame is complete
+pub struct FrameParser {
+    buffer: [u8; FRAME_SIZE],
+    len: usize,
+}
+
+impl FrameParser {
+    pub const fn new() -> Self {
+        Self {
+            buffer: [0; FRAME_SIZE],
+            len: 0,
+        }
+    }
+
+    /// Add a received byte. Returns the frame once a valid one is complete
+    pub fn feed(&mut self, byte: u8) -> Option<Frame> {
+        // Wait for the start of a frame
+        if self.len == 0 && byte != FRAME_START {
+            return None;
+        }
+
+        self.buffer[self.len] = byte;
+        self.len += 1;
+
+        if self.len < FRAME_SIZE {
+            return None;
+        }
+
+        let frame = Frame::decode(&self.buffer);
+        if frame.is_some() {
+            self.len = 0;
+        } else {
+            // Resync on the next start byte within the broken frame
+            let skip = self.buffer[1..]
+                .iter()
+                .position(|byte| *byte == FRAME_START)
+                .map_or(FRAME_SIZE, |pos| pos + 1);
+            self.buffer.copy_within(skip.., 0);
+            self.len = FRAME_SIZE - skip;
+        }
+        frame
+    }
+}
+
+impl Default for FrameParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Messages send by the DFPlayer
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Response {
+    /// A track on the USB drive (0x3C) or the SD card (0x3D) has finished
+    TrackFinished(u16),
+    /// A media has been inserted (0x3A)
+    MediaInserted(u16),
+    /// A media has been removed (0x3B)
+    MediaRemoved(u16),
+    /// The module is ready after power on or reset (0x3F) with the attached media
+    Online(u16),
+    /// The module reported an error (0x40)
+    Error(u8),
+    /// A command has been acknowledged (0x41)
+    Ack,
+    /// Any other frame, e.g. answers to queries
+    Other(Frame),
+}
+
+impl From<Frame> for Response {
+    fn from(frame: Frame) -> Self {
+        match frame.cmd {
+            0x3C | 0x3D => Response::TrackFinished(frame.param),
+            0x3A => Response::MediaInserted(frame.param),
+            0x3B => Response::MediaRemoved(frame.param),
+            0x3F => Response::Online(frame.param),
+            0x40 => Response::Error(frame.param as u8),
+            0x41 => Response::Ack,
+            _ => Response::Other(frame),
+        }
+    }
+}
+
+/// Media flag for the SD card in insert, remove and online notifications
+pub const MEDIA_SD: u16 = 0x02;
+
+/// Monotonic time source of the driver, it waits for answers and keeps the gap between frames
+pub trait Clock {
+    type Instant: Copy + PartialOrd + Add<Self::Duration, Output = Self::Instant>;
+    type Duration: Copy;
+
+    /// Current time
+    fn now(&self) -> Self::Instant;
+
+    /// Convert milliseconds to a duration of the clock
+    fn millis(&self, ms: u32) -> Self::Duration;
+}
+
+/// Errors of the DFPlayer driver
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Error {
+    /// Writing to the serial port failed
+    Serial,
+    /// No answer within the timeout
+    Timeout,
+    /// The module answered with an error code
+    Module(u8),
+    /// The answer could not be interpreted
+    InvalidAnswer(u16),
+    /// The command was not acknowledged in reliable mode
+    NoAck,
+}
+
+pub struct DFPlayer<S, C: Clock> {
+    serial: S,
+    clock: C,
+    parser: FrameParser,
+    /// Notifications received while waiting for an answer
+    pending: heapless::spsc::Queue<Response, heapless::consts::U4>,
+    /// Time to wait for an answer
+    timeout: C::Duration,
+    /// Number of retries if commands must be acknowledged (reliable mode)
+    ack_retries: Option<u8>,
+    /// Minimum time between two frames
+    min_gap: C::Duration,
+    /// Time the last frame was send
+    last_frame: Option<C::Instant>,
+    /// The end of a track is detected with the BUSY pin
+    busy_tracking: bool,
+    /// BUSY pin reports playing
+    busy_playing: bool,
+    /// A command has been send that stops the current track
+    expect_stop: bool,
+    /// Media flags of the attached media
+    media: u16,
+    /// Behaviour of the chip on the module
+    quirks: Quirks,
+    /// Last track finished notification to drop duplicates
+    last_finished: Option<(u16, C::Instant)>,
+    /// Configured equalizer preset
+    equalizer: Equalizer,
+    /// Configured playback device
+    device: PlaybackDevice,
+    /// The equalizer preset has to be send
+    equalizer_pending: bool,
+    /// The playback device has to be send. This stops the playback, so it is only done after a
+    /// reset, a wake up or a media change and if the device is changed.
+    device_pending: bool,
+    /// The module is in standby mode
+    sleeping: bool,
+}
+
+impl<S, C> DFPlayer<S, C>
+where
+    S: Read<u8> + Write<u8>,
+    C: Clock,
+{
+    /// Create the driver from any serial port configured with 9600 baud 8N1
+    pub fn new(serial: S, clock: C) -> Self {
+        Self {
+            serial,
+            parser: FrameParser::new(),
+            pending: heapless::spsc::Queue::new(),
+            timeout: clock.millis(DEFAULT_TIMEOUT_MS),
+            ack_retries: None,
+            min_gap: clock.millis(DEFAULT_GAP_MS),
+            clock,
+            last_frame: None,
+            busy_tracking: false,
+            busy_playing: false,
+            expect_stop: false,
+            media: 0,
+            quirks: ChipVariant::Yx5200.quirks(),
+            last_finished: None,
+            equalizer: Equalizer::Normal,
+            device: PlaybackDevice::SdCard,
+            equalizer_pending: false,
+            device_pending: false,
+            sleeping: false,
+        }
+    }
+
+    /// Set equalizer preset and playback device. Only changed values are send, both are send
+    /// again after every reset, wake up or insertion of a media.
+    pub fn configure(&mut self, equalizer: Equalizer, device: PlaybackDevice) {
+        self.equalizer_pending |= equalizer != self.equalizer;
+        self.device_pending |= device != self.device;
+        self.equalizer = equalizer;
+        self.device = device;
+    }
+
+    /// The configuration has to be send with `apply_config`
+    pub fn config_pending(&self) -> bool {
+        self.equalizer_pending || self.device_pending
+    }
+
+    /// The module forgot the configuration, send all of it again
+    fn config_lost(&mut self) {
+        self.equalizer_pending = true;
+        self.device_pending = true;
+    }
+
+    /// Send the next pending part of the configuration, the playback device first. Every call
+    /// sends one command, so it is called until `config_pending` is false. A failed command is
+    /// not repeated.
+    pub fn apply_config(&mut self) -> Result<(), Error> {
+        if self.device_pending {
+            self.device_pending = false;
+            self.send(Command::SetPlaybackDevice(self.device))
+        } else if self.equalizer_pending {
+            self.equalizer_pending = false;
+            self.send(Command::SetEqualizer(self.equalizer))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Select the chip on the module to work around its quirks
+    pub fn set_chip_variant(&mut self, chip: ChipVariant) {
+        self.quirks = chip.quirks();
+    }
+
+    /// Detect the end of a track with the BUSY pin instead of the serial notifications.
+    /// The pin changes have to be passed to `busy_changed`.
+    pub fn enable_busy_tracking(&mut self) {
+        self.busy_tracking = true;
+    }
+
+    /// Handle a change of the BUSY pin (high = idle, low = playing).
+    /// Returns `TrackFinished(0)` if the track stopped without a command that stops it.
+    /// The pin does not tell the track number.
+    pub fn busy_changed(&mut self, busy_high: bool) -> Option<Response> {
+        let was_playing = self.busy_playing;
+        self.busy_playing = !busy_high;
+
+        if self.busy_playing {
+            // The new track started
+            self.expect_stop = false;
+            None
+        } else if was_playing && !self.expect_stop {
+            Some(Response::TrackFinished(0))
+        } else {
+            None
+        }
+    }
+
+    /// Set the minimum time between two frames. The module drops frames that follow too fast.
+    pub fn set_min_gap(&mut self, min_gap: C::Duration) {
+        self.min_gap = min_gap;
+    }
+
+    /// Earliest time the next frame may be send
+    pub fn ready_at(&self) -> Option<C::Instant> {
+        self.last_frame.map(|last_frame| last_frame + self.min_gap)
+    }
+
+    /// Enable the reliable mode with the given number of retries or disable it with None.
+    /// In reliable mode every command requests an acknowledgement from the module.
+    pub fn set_ack_retries(&mut self, retries: Option<u8>) {
+        self.ack_retries = retries;
+    }
+
+    /// Set the time to wait for an answer of the module
+    pub fn set_timeout(&mut self, timeout: C::Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Release the serial port
+    pub fn release(self) -> S {
+        self.serial
+    }
+
+    /// Read the received bytes. Returns a response as soon as a valid frame is complete
+    pub fn poll(&mut self) -> Option<Response> {
+        loop {
+            let response = match self.pending.dequeue() {
+                Some(response) => response,
+                None => self.receive()?,
+            };
+            match response {
+                // The BUSY pin reports the end of the track
+                Response::TrackFinished(_) if self.busy_tracking => (),
+                response => return Some(response),
+            }
+        }
+    }
+
+    /// Read the received bytes without looking at the pending notifications
+    fn receive(&mut self) -> Option<Response> {
+        loop {
+            match self.serial.read() {
+                Ok(byte) => {
+                    if let Some(frame) = self.parser.feed(byte) {
+                        let response = Response::from(frame);
+                        self.update_media(&response);
+                        if !self.is_duplicate(&response) {
+                            return Some(response);
+                        }
+                    }
+                }
+                // The error flags are cleared by the read, just go on with the next byte
+                Err(nb::Error::Other(_)) => (),
+                Err(nb::Error::WouldBlock) => return None,
+            }
+        }
+    }
+
+    /// Check for a repeated track finished notification
+    fn is_duplicate(&mut self, response: &Response) -> bool {
+        let track = match *response {
+            Response::TrackFinished(track) => track,
+            _ => return false,
+        };
+        if !self.quirks.duplicate_track_finished {
+            return false;
+        }
+
+        let now = self.clock.now();
+        let duplicate = match self.last_finished {
+            Some((last_track, time)) => {
+                last_track == track && now < time + self.clock.millis(DUPLICATE_WINDOW_MS)
+            }
+            None => false,
+        };
+        self.last_finished = Some((track, now));
+        duplicate
+    }
+
+    /// Keep track of the attached media.
+    /// The configuration is lost on reset and media changes.
+    fn update_media(&mut self, response: &Response) {
+        match *response {
+            Response::Online(media) => {
+                self.media = media;
+                self.config_lost();
+                self.sleeping = false;
+            }
+            Response::MediaInserted(media) => {
+                self.media |= media;
+                self.config_lost();
+            }
+            Response::MediaRemoved(media) => self.media &= !media,
+            _ => (),
+        }
+    }
+
+    /// Media flags of the attached media
+    pub fn media(&self) -> u16 {
+        self.media
+    }
+
+    /// A SD card is inserted
+    pub fn has_sd_card(&self) -> bool {
+        self.media & MEDIA_SD != 0
+    }
+
+    /// Reset the module and wait until it is online. Returns the attached media.
+    pub fn startup(&mut self) -> Result<u16, Error> {
+        // The reset is not acknowledged, so don't use the reliable mode
+        self.expect_stop = true;
+        self.write_frame(&Command::Reset.frame(false))?;
+
+        let boot_time = self.clock.millis(self.quirks.boot_ms);
+        if self.quirks.sends_online {
+            self.wait_for_within(boot_time, |response| match response {
+                Response::Online(media) => Some(*media),
+                _ => None,
+            })
+        } else {
+            // Wait for the boot and ask for files on the SD card instead
+            let booted = self.clock.now() + boot_time;
+            while self.clock.now() < booted {}
+            self.media = if self.file_count()? > 0 { MEDIA_SD } else { 0 };
+            self.config_lost();
+            Ok(self.media)
+        }
+    }
+
+    /// Send a command to the DFPlayer
+    /// In reliable mode the command is repeated until the module acknowledges it.
+    pub fn send(&mut self, command: Command) -> Result<(), Error> {
+        // The BUSY pin goes high for these commands, this is not the end of a track
+        match command {
+            Command::Pause
+            | Command::Standby
+            | Command::Reset
+            | Command::Next
+            | Command::Previous
+            | Command::PlayFolderTrack(_, _)
+            | Command::PlayMp3(_) => self.expect_stop = true,
+            _ => (),
+        }
+        // The end of the started track is no duplicate, even if the same track ended before
+        if command.starts_playback() {
+            self.last_finished = None;
+        }
+        match command {
+            Command::Standby => self.sleeping = true,
+            Command::Wake | Command::Reset => self.sleeping = false,
+            _ => (),
+        }
+
+        let retries = match self.ack_retries {
+            Some(retries) if self.quirks.sends_ack => retries,
+            _ => return self.write_frame(&command.frame(false)),
+        };
+
+        for _ in 0..=retries {
+            self.write_frame(&command.frame(true))?;
+            match self.wait_for(|response| match response {
+                Response::Ack => Some(()),
+                _ => None,
+            }) {
+                Err(Error::Timeout) => (),
+                result => return result,
+            }
+        }
+        Err(Error::NoAck)
+    }
+
+    /// Write a complete frame to the serial port
+    fn write_frame(&mut self, frame: &[u8; FRAME_SIZE]) -> Result<(), Error> {
+        // Keep the minimum gap to the last frame
+        if let Some(ready_at) = self.ready_at() {
+            while self.clock.now() < ready_at {}
+        }
+        self.last_frame = Some(self.clock.now());
+
+        for byte in frame.iter() {
+            nb::block!(self.serial.write(*byte)).map_err(|_| Error::Serial)?;
+        }
+        nb::block!(self.serial.flush()).map_err(|_| Error::Serial)
+    }
+
+    /// Send a query and wait for the answer with the same command byte (the folder track
+    /// count answer depends on the chip).
+    /// Notifications received in the meantime are kept for `poll`.
+    pub fn query(&mut self, query: Query) -> Result<u16, Error> {
+        self.write_frame(&query.frame())?;
+        let answer = match query {
+            Query::FolderTrackCount(_) => self.quirks.folder_count_answer,
+            _ => query.code(),
+        };
+        self.wait_for(|response| match response {
+            Response::Other(frame) if frame.cmd == answer => Some(frame.param),
+            _ => None,
+        })
+    }
+
+    /// Wait until `matches` accepts a response or an error is reported.
+    /// All other responses are kept for `poll`.
+    fn wait_for<T>(&mut self, matches: impl FnMut(&Response) -> Option<T>) -> Result<T, Error> {
+        self.wait_for_within(self.timeout, matches)
+    }
+
+    /// Like `wait_for` with a different timeout
+    fn wait_for_within<T>(
+        &mut self,
+        timeout: C::Duration,
+        mut matches: impl FnMut(&Response) -> Option<T>,
+    ) -> Result<T, Error> {
+        let deadline = self.clock.now() + timeout;
+        while self.clock.now() < deadline {
+            match self.receive() {
+                Some(Response::Error(code)) => return Err(Error::Module(code)),
+                Some(response) => {
+                    if let Some(result) = matches(&response) {
+                        return Ok(result);
+                    }
+                    // Keep it for the application, drop it if there are already too many
+                    self.pending.enqueue(response).ok();
+                }
+                None => (),
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    /// Current playback state
+    pub fn status(&mut self) -> Result<PlayState, Error> {
+        let status = self.query(Query::Status)?;
+        PlayState::try_from(status).map_err(|_| Error::InvalidAnswer(status))
+    }
+
+    /// Current volume (0..=30)
+    pub fn volume(&mut self) -> Result<u8, Error> {
+        self.query(Query::Volume).map(|volume| volume as u8)
+    }
+
+    /// Number of the current track on the SD card
+    pub fn current_track(&mut self) -> Result<u16, Error> {
+        self.query(Query::CurrentTrack)
+    }
+
+    /// Number of files on the SD card
+    pub fn file_count(&mut self) -> Result<u16, Error> {
+        self.query(Query::FileCount)
+    }
+
+    /// Number of tracks in the folder
+    pub fn folder_track_count(&mut self, folder: u8) -> Result<u16, Error> {
+        self.query(Query::FolderTrackCount(folder))
+    }
+
+    /// Play a track from a folder
+    pub fn play(&mut self, folder: u8, track: u8) -> Result<(), Error> {
+        self.send(Command::PlayFolderTrack(folder, track))
+    }
+
+    /// Pause the current track
+    pub fn pause(&mut self) -> Result<(), Error> {
+        self.send(Command::Pause)
+    }
+
+    /// Resume the paused track
+    pub fn resume(&mut self) -> Result<(), Error> {
+        self.send(Command::Resume)
+    }
+
+    /// Play the next track
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<(), Error> {
+        self.send(Command::Next)
+    }
+
+    /// Play the previous track
+    pub fn previous(&mut self) -> Result<(), Error> {
+        self.send(Command::Previous)
+    }
+
+    /// Set the volume (0..=30)
+    pub fn set_volume(&mut self, volume: u8) -> Result<(), Error> {
+        self.send(Command::SetVolume(volume))
+    }
+
+    /// Increase the volume by one step
+    pub fn volume_up(&mut self) -> Result<(), Error> {
+        self.send(Command::VolumeUp)
+    }
+
+    /// Decrease the volume by one step
+    pub fn volume_down(&mut self) -> Result<(), Error> {
+        self.send(Command::VolumeDown)
+    }
+
+    /// Reset the module
+    pub fn reset(&mut self) -> Result<(), Error> {
+        self.send(Command::Reset)
+    }
+
+    /// Enter standby mode
+    pub fn standby(&mut self) -> Result<(), Error> {
+        self.send(Command::Standby)
+    }
+
+    /// Leave standby mode. The playback device has to be selected again afterwards, so the
+    /// configuration is pending.
+    pub fn wake(&mut self) -> Result<(), Error> {
+        self.send(Command::Wake)?;
+        self.config_lost();
+        Ok(())
+    }
+
+    /// The module is in standby mode and has to be woken up before playing
+    pub fn is_sleeping(&self) -> bool {
+        self.sleeping
+    }
+
+    /// Select the equalizer preset
+    pub fn set_equalizer(&mut self, equalizer: Equalizer) -> Result<(), Error> {
+        self.send(Command::SetEqualizer(equalizer))
+    }
+
+    /// Select the device the tracks are played from
+    pub fn set_playback_device(&mut self, device: PlaybackDevice) -> Result<(), Error> {
+        self.send(Command::SetPlaybackDevice(device))
+    }
+}
+
+/// Amplifier with an enable pin (high enables the speaker). It is muted while the DFPlayer
+/// starts or stops playing to avoid pops and while nothing plays to avoid hiss.
+pub struct Amplifier<P> {
+    pin: P,
+}
+
+impl<P: OutputPin> Amplifier<P> {
+    /// The amplifier starts muted
+    pub fn new(mut pin: P) -> Self {
+        pin.set_low().ok();
+        Self { pin }
+    }
+
+    pub fn mute(&mut self) {
+        self.pin.set_low().ok();
+    }
+
+    pub fn unmute(&mut self) {
+        self.pin.set_high().ok();
+    }
+}
+
+/// Bounded queue of commands waiting to be send to the DFPlayer.
+/// Redundant commands are merged, e.g. several volume changes become one `SetVolume`.
+pub struct CommandQueue {
+    commands: heapless::Vec<Command, heapless::consts::U8>,
+    /// Volume after all queued commands are send
+    volume: u8,
+}
+
+impl CommandQueue {
+    pub fn new(volume: u8) -> Self {
+        Self {
+            commands: heapless::Vec::new(),
+            volume: volume.min(MAX_VOLUME),
+        }
+    }
+
+    /// Volume after all queued commands are send
+    pub fn volume(&self) -> u8 {
+        self.volume
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    /// Add a command. Returns the command if the queue is full
+    pub fn push(&mut self, command: Command) -> Result<(), Command> {
+        let command = match command {
+            Command::VolumeUp => Command::SetVolume(self.volume.saturating_add(1)),
+            Command::VolumeDown => Command::SetVolume(self.volume.saturating_sub(1)),
+            other => other,
+        };
+
+        match command {
+            Command::SetVolume(volume) => {
+                let volume = volume.min(MAX_VOLUME);
+                // Merge with a volume change that is still waiting at the end of the queue.
+                // Earlier ones are kept, they may belong to a play command (e.g. a fade in).
+                if let Some(Command::SetVolume(queued)) = self.commands.last_mut() {
+                    *queued = volume;
+                } else {
+                    self.commands.push(Command::SetVolume(volume))?;
+                }
+                // A dropped change does not count for the following ones
+                self.volume = volume;
+                Ok(())
+            }
+            Command::Pause | Command::Resume => {
+                self.remove(|queued| matches!(queued, Command::Pause | Command::Resume));
+                self.commands.push(command)
+            }
+            Command::PlayFolderTrack(_, _) => {
+                // A new track replaces everything that changes the track or the play state
+                self.remove(|queued| {
+                    matches!(
+                        queued,
+                        Command::PlayFolderTrack(_, _)
+                            | Command::Next
+                            | Command::Previous
+                            | Command::Pause
+                            | Command::Resume
+                    )
+                });
+                self.commands.push(command)
+            }
+            other => self.commands.push(other),
+        }
+    }
+
+    /// Oldest command without taking it
+    pub fn peek(&self) -> Option<&Command> {
+        self.commands.first()
+    }
+
+    /// Take the oldest command
+    pub fn pop(&mut self) -> Option<Command> {
+        let command = self.commands.first().copied()?;
+        self.commands.rotate_left(1);
+        self.commands.pop();
+        Some(command)
+    }
+
+    /// Remove all queued commands matching the predicate
+    fn remove(&mut self, predicate: impl Fn(&Command) -> bool) {
+        let queued = core::mem::replace(&mut self.commands, heapless::Vec::new());
+        for command in queued.iter().filter(|command| !predicate(command)) {
+            self.commands.push(*command).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::collections::VecDeque;
+
+    /// UART that answers from a prepared buffer and records the written bytes
+    #[derive(Default)]
+    struct MockSerial {
+        rx: VecDeque<u8>,
+        tx: Vec<u8>,
+    }
+
+    impl Read<u8> for MockSerial {
+        type Error = ();
+
+        fn read(&mut self) -> nb::Result<u8, ()> {
+            self.rx.pop_front().ok_or(nb::Error::WouldBlock)
+        }
+    }
+
+    impl Write<u8> for MockSerial {
+        type Error = ();
+
+        fn write(&mut self, byte: u8) -> nb::Result<(), ()> {
+            self.tx.push(byte);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> nb::Result<(), ()> {
+            Ok(())
+        }
+    }
+
+    /// Clock that advances by one millisecond every time it is read
+    #[derive(Default)]
+    struct MockClock {
+        now: Cell<u32>,
+    }
+
+    impl Clock for MockClock {
+        type Instant = u32;
+        type Duration = u32;
+
+        fn now(&self) -> u32 {
+            let now = self.now.get();
+            self.now.set(now + 1);
+            now
+        }
+
+        fn millis(&self, ms: u32) -> u32 {
+            ms
+        }
+    }
+
+    fn player(received: &[&[u8; FRAME_SIZE]]) -> DFPlayer<MockSerial, MockClock> {
+        let mut serial = MockSerial::default();
+        for frame in received {
+            serial.rx.extend(frame.iter());
+        }
+        DFPlayer::new(serial, MockClock::default())
+    }
+
+    #[test]
+    fn checksum_of_reference_frame() {
+        assert_eq!(checksum(&[0xFF, 0x06, 0x0F, 0x00, 0x01, 0x01]), 0xFEEA);
+    }
+
+    #[test]
+    fn frame_play_folder_track() {
+        let expected = [0x7E, 0xFF, 0x06, 0x0F, 0x00, 0x01, 0x01, 0xFE, 0xEA, 0xEF];
+        assert_eq!(frame(0x0F, false, 0x0101), expected);
+        assert_eq!(Command::PlayFolderTrack(1, 1).frame(false), expected);
+    }
+
+    #[test]
+    fn frame_play_folder_track_with_ack() {
+        let expected = [0x7E, 0xFF, 0x06, 0x0F, 0x01, 0x01, 0x01, 0xFE, 0xE9, 0xEF];
+        assert_eq!(Command::PlayFolderTrack(1, 1).frame(true), expected);
+    }
+
+    #[test]
+    fn frame_set_volume() {
+        let expected = [0x7E, 0xFF, 0x06, 0x06, 0x00, 0x00, 0x0F, 0xFE, 0xE6, 0xEF];
+        assert_eq!(Command::SetVolume(15).frame(false), expected);
+        // The volume is limited to the maximum
+        let expected = [0x7E, 0xFF, 0x06, 0x06, 0x00, 0x00, 0x1E, 0xFE, 0xD7, 0xEF];
+        assert_eq!(Command::SetVolume(40).frame(false), expected);
+    }
+
+    #[test]
+    fn frame_reset() {
+        let expected = [0x7E, 0xFF, 0x06, 0x0C, 0x00, 0x00, 0x00, 0xFE, 0xEF, 0xEF];
+        assert_eq!(Command::Reset.frame(false), expected);
+    }
+
+    #[test]
+    fn decode_reference_frame() {
+        let bytes = [0x7E, 0xFF, 0x06, 0x0F, 0x00, 0x01, 0x01, 0xFE, 0xEA, 0xEF];
+        assert_eq!(
+            Frame::decode(&bytes),
+            Some(Frame {
+                cmd: 0x0F,
+                param: 0x0101
+            })
+        );
+    }
+
+    #[test]
+    fn query_keeps_notifications() {
+        let mut player = player(&[&frame(0x3D, false, 7), &frame(0x43, false, 20)]);
+        assert_eq!(player.volume(), Ok(20));
+        assert_eq!(player.poll(), Some(Response::TrackFinished(7)));
+        assert_eq!(player.poll(), None);
+        assert_eq!(player.release().tx, Query::Volume.frame());
+    }
+
+    #[test]
+    fn query_without_answer_times_out() {
+        let mut player = player(&[]);
+        assert_eq!(player.status(), Err(Error::Timeout));
+    }
+
+    #[test]
+    fn query_reports_module_error() {
+        let mut player = player(&[&frame(0x40, false, 0x06)]);
+        assert_eq!(player.folder_track_count(3), Err(Error::Module(0x06)));
+    }
+
+    #[test]
+    fn broken_frames_are_skipped() {
+        let mut broken = frame(0x3D, false, 1);
+        broken[8] ^= 0xFF;
+        let mut player = player(&[&broken, &frame(0x3A, false, MEDIA_SD)]);
+        assert_eq!(player.poll(), Some(Response::MediaInserted(MEDIA_SD)));
+        assert!(player.has_sd_card());
+        assert!(player.config_pending());
+    }
+
+    #[test]
+    fn only_changed_config_is_send() {
+        let mut player = player(&[]);
+        player.configure(Equalizer::Rock, PlaybackDevice::SdCard);
+        assert!(player.config_pending());
+        assert_eq!(player.apply_config(), Ok(()));
+        assert!(!player.config_pending());
+        // The same configuration again, e.g. the preview of another menu item
+        player.configure(Equalizer::Rock, PlaybackDevice::SdCard);
+        assert!(!player.config_pending());
+        assert_eq!(
+            player.release().tx,
+            Command::SetEqualizer(Equalizer::Rock).frame(false)
+        );
+    }
+
+    #[test]
+    fn whole_config_is_send_after_reset() {
+        let mut player = player(&[&frame(0x3F, false, MEDIA_SD)]);
+        player.configure(Equalizer::Jazz, PlaybackDevice::SdCard);
+        player.apply_config().unwrap();
+        assert_eq!(player.poll(), Some(Response::Online(MEDIA_SD)));
+        assert!(player.config_pending());
+        player.apply_config().unwrap();
+        assert!(player.config_pending());
+        player.apply_config().unwrap();
+        assert!(!player.config_pending());
+        let sent = player.release().tx;
+        let expected = [
+            Command::SetEqualizer(Equalizer::Jazz).frame(false),
+            Command::SetPlaybackDevice(PlaybackDevice::SdCard).frame(false),
+            Command::SetEqualizer(Equalizer::Jazz).frame(false),
+        ]
+        .concat();
+        assert_eq!(sent, expected);
+    }
+
+    #[test]
+    fn duplicate_track_finished_is_dropped() {
+        let finished = frame(0x3D, false, 4);
+        let mut player = player(&[&finished, &finished]);
+        assert_eq!(player.poll(), Some(Response::TrackFinished(4)));
+        assert_eq!(player.poll(), None);
+    }
+
+    #[test]
+    fn replayed_track_is_no_duplicate() {
+        let finished = frame(0x3D, false, 4);
+        let mut player = player(&[&finished, &finished]);
+        assert_eq!(player.poll(), Some(Response::TrackFinished(4)));
+        // A short prompt is played twice in a row
+        player.send(Command::PlayMp3(4)).unwrap();
+        assert_eq!(player.poll(), Some(Response::TrackFinished(4)));
+    }
+
+    #[test]
+    fn reliable_mode_without_ack_fails() {
+        let mut player = player(&[]);
+        player.set_ack_retries(Some(2));
+        assert_eq!(player.pause(), Err(Error::NoAck));
+        // The command has been send three times with the ack flag
+        let sent = player.release().tx;
+        assert_eq!(sent.len(), 3 * FRAME_SIZE);
+        assert!(sent
+            .chunks(FRAME_SIZE)
+            .all(|sent| sent == Command::Pause.frame(true)));
+    }
+
+    /// Take all commands of the queue in the order they are send
+    fn drain(queue: &mut CommandQueue) -> Vec<Command> {
+        core::iter::from_fn(|| queue.pop()).collect()
+    }
+
+    #[test]
+    fn volume_changes_are_merged() {
+        let mut queue = CommandQueue::new(10);
+        for _ in 0..3 {
+            queue.push(Command::VolumeUp).unwrap();
+        }
+        queue.push(Command::VolumeDown).unwrap();
+        assert_eq!(queue.volume(), 12);
+        assert_eq!(drain(&mut queue), [Command::SetVolume(12)]);
+
+        // The limit of the DFPlayer is kept
+        let mut queue = CommandQueue::new(MAX_VOLUME);
+        queue.push(Command::VolumeUp).unwrap();
+        assert_eq!(drain(&mut queue), [Command::SetVolume(MAX_VOLUME)]);
+    }
+
+    #[test]
+    fn volume_before_a_track_is_kept() {
+        let mut queue = CommandQueue::new(10);
+        queue.push(Command::SetVolume(0)).unwrap();
+        queue.push(Command::PlayFolderTrack(1, 1)).unwrap();
+        queue.push(Command::SetVolume(5)).unwrap();
+        queue.push(Command::VolumeUp).unwrap();
+        assert_eq!(
+            drain(&mut queue),
+            [
+                Command::SetVolume(0),
+                Command::PlayFolderTrack(1, 1),
+                Command::SetVolume(6)
+            ]
+        );
+    }
+
+    #[test]
+    fn play_state_commands_replace_each_other() {
+        let mut queue = CommandQueue::new(10);
+        queue.push(Command::Pause).unwrap();
+        queue.push(Command::Resume).unwrap();
+        assert_eq!(drain(&mut queue), [Command::Resume]);
+
+        queue.push(Command::Next).unwrap();
+        queue.push(Command::Pause).unwrap();
+        queue.push(Command::PlayFolderTrack(1, 2)).unwrap();
+        assert_eq!(drain(&mut queue), [Command::PlayFolderTrack(1, 2)]);
+
+        queue.push(Command::PlayFolderTrack(1, 2)).unwrap();
+        queue.push(Command::Pause).unwrap();
+        queue.push(Command::PlayFolderTrack(2, 3)).unwrap();
+        assert_eq!(drain(&mut queue), [Command::PlayFolderTrack(2, 3)]);
+    }
+
+    #[test]
+    fn full_queue_keeps_the_volume() {
+        let mut queue = CommandQueue::new(10);
+        while queue.push(Command::Next).is_ok() {}
+        assert_eq!(queue.push(Command::VolumeUp), Err(Command::SetVolume(11)));
+        assert_eq!(queue.volume(), 10);
+
+        queue.pop();
+        queue.push(Command::VolumeUp).unwrap();
+        assert_eq!(queue.volume(), 11);
+        assert_eq!(drain(&mut queue).last(), Some(&Command::SetVolume(11)));
+    }
+}
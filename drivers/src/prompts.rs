@@ -0,0 +1,63 @@
+//! Spoken numbers from the prompts in the `mp3` folder of the SD card
+
+/// Prompt in the `mp3` folder for "zero"
+const PROMPT_ZERO: u16 = 100;
+/// Prompts in the `mp3` folder for "one hundred" to "nine hundred" start after this
+const PROMPT_HUNDREDS: u16 = 100;
+/// Prompt in the `mp3` folder for "thousand"
+const PROMPT_THOUSAND: u16 = 110;
+
+/// Prompts in the `mp3` folder that speak a number.
+/// The files `0001` to `0099` speak the numbers 1 to 99, larger numbers are composed.
+pub fn number_prompts(number: u16) -> heapless::Vec<u16, heapless::consts::U4> {
+    let mut prompts = heapless::Vec::new();
+
+    if number == 0 {
+        prompts.push(PROMPT_ZERO).ok();
+        return prompts;
+    }
+
+    // At most 65 thousands, which is a single prompt
+    let thousands = number / 1000;
+    if thousands > 0 {
+        prompts.push(thousands).ok();
+        prompts.push(PROMPT_THOUSAND).ok();
+    }
+
+    let hundreds = number % 1000 / 100;
+    if hundreds > 0 {
+        prompts.push(PROMPT_HUNDREDS + hundreds).ok();
+    }
+
+    let rest = number % 100;
+    if rest > 0 {
+        prompts.push(rest).ok();
+    }
+
+    prompts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_numbers_are_single_prompts() {
+        assert_eq!(number_prompts(0), [PROMPT_ZERO]);
+        assert_eq!(number_prompts(1), [1]);
+        assert_eq!(number_prompts(99), [99]);
+    }
+
+    #[test]
+    fn hundreds_and_thousands_are_composed() {
+        assert_eq!(number_prompts(100), [101]);
+        assert_eq!(number_prompts(305), [103, 5]);
+        assert_eq!(number_prompts(1000), [1, PROMPT_THOUSAND]);
+        assert_eq!(number_prompts(2017), [2, PROMPT_THOUSAND, 17]);
+    }
+
+    #[test]
+    fn largest_number_fits() {
+        assert_eq!(number_prompts(65535), [65, PROMPT_THOUSAND, 105, 35]);
+    }
+}
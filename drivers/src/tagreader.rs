@@ -0,0 +1,1142 @@
+use crate::card::Card;
+use core::convert::TryFrom;
+
+/// Default block with the card data on MIFARE Classic tags
+pub const DEFAULT_CARD_BLOCK: u8 = 0x04;
+/// Most keys that are tried for the authentication
+pub type MaxKeys = heapless::consts::U4;
+/// First user page with the card data on NTAG21x tags
+const NTAG_FIRST_PAGE: u8 = 0x04;
+/// NTAG21x tags are organized in pages of 4 bytes
+const NTAG_PAGE_SIZE: usize = 4;
+
+/// Tag families with a different memory layout
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TagType {
+    /// MIFARE Classic 1K/4K with 16 byte blocks and authentication
+    MifareClassic,
+    /// NTAG213/215/216 (MIFARE Ultralight family) with 4 byte pages
+    Ntag,
+}
+
+impl TagType {
+    /// Type from the ATQA of the wakeup, the low byte is sent first. NTAG21x and the other
+    /// Ultralight tags answer 0x0044, MIFARE Classic 1K 0x0004 and MIFARE Classic 4K 0x0002.
+    /// Other answers are ambiguous.
+    fn from_atqa(atqa: &mfrc522::AtqA) -> Option<Self> {
+        match atqa.as_bytes() {
+            [0x44, 0x00] => Some(TagType::Ntag),
+            [0x04, 0x00] | [0x02, 0x00] => Some(TagType::MifareClassic),
+            _ => None,
+        }
+    }
+
+    /// Fallback for ambiguous ATQAs. NTAG21x always have a 7 byte UID, MIFARE Classic mostly
+    /// a 4 byte UID. The other type is tried if the guess is wrong.
+    fn guess(uid: &mfrc522::Uid) -> Self {
+        match uid {
+            mfrc522::Uid::Single(_) => TagType::MifareClassic,
+            _ => TagType::Ntag,
+        }
+    }
+
+    fn other(self) -> Self {
+        match self {
+            TagType::MifareClassic => TagType::Ntag,
+            TagType::Ntag => TagType::MifareClassic,
+        }
+    }
+}
+
+/// Key type used for the authentication of MIFARE Classic sectors
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum KeyType {
+    A,
+    B,
+}
+
+/// Key of a MIFARE Classic sector
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct CardKey {
+    pub key_type: KeyType,
+    pub bytes: [u8; 6],
+}
+
+impl CardKey {
+    /// Transport key A of new cards
+    pub const DEFAULT: CardKey = CardKey::new(KeyType::A, [0xFF; 6]);
+
+    pub const fn new(key_type: KeyType, bytes: [u8; 6]) -> Self {
+        Self { key_type, bytes }
+    }
+
+    fn mifare_key(&self) -> mfrc522::mifare::Key {
+        match self.key_type {
+            KeyType::A => mfrc522::mifare::Key::KeyA(self.bytes),
+            KeyType::B => mfrc522::mifare::Key::KeyB(self.bytes),
+        }
+    }
+}
+
+/// Blocks of a MIFARE Classic 1K card. The card block is one of them, so the card data can be
+/// written to 1K and 4K cards.
+pub const CLASSIC_1K_BLOCKS: u8 = 64;
+
+/// Check if a MIFARE Classic block can hold the card data. Block 0 contains the manufacturer
+/// data and the last block of each sector the keys.
+pub fn is_data_block(block: u8) -> bool {
+    block != 0 && block < CLASSIC_1K_BLOCKS && block % 4 != 3
+}
+
+/// General purpose byte of the sector trailer, it is not used by the card
+const TRAILER_GPB: u8 = 0x69;
+
+/// Trailer block of the sector with the data block
+fn trailer_block(block: u8) -> Option<u8> {
+    if is_data_block(block) {
+        Some(block | 0x03)
+    } else {
+        None
+    }
+}
+
+/// Access conditions (C1 C2 C3) of the blocks 0 to 2 and the trailer of a sector
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct AccessBits {
+    conditions: [u8; 4],
+}
+
+impl AccessBits {
+    /// Access bits of new cards, everything is allowed with key A
+    pub const TRANSPORT: AccessBits = AccessBits {
+        conditions: [0b000, 0b000, 0b000, 0b001],
+    };
+    /// Data blocks are readable with key A or B and only writable with key B.
+    /// Keys and access bits are only writable with key B.
+    pub const LOCKED: AccessBits = AccessBits {
+        conditions: [0b100, 0b100, 0b100, 0b011],
+    };
+
+    /// Only trailer conditions that keep keys and access bits writable are accepted,
+    /// all others lock the sector forever
+    fn is_safe(&self) -> bool {
+        self.conditions.iter().all(|condition| *condition <= 0b111)
+            && (self.conditions[3] == 0b001 || self.conditions[3] == 0b011)
+    }
+
+    /// Bytes 6 to 8 of the trailer. Every bit is stored inverted as well. None if the
+    /// conditions are not safe.
+    pub fn to_bytes(&self) -> Option<[u8; 3]> {
+        if !self.is_safe() {
+            return None;
+        }
+        let (c1, c2, c3) = self.nibbles();
+        let bytes = [
+            (!c2 << 4) | (!c1 & 0x0F),
+            (c1 << 4) | (!c3 & 0x0F),
+            (c3 << 4) | c2,
+        ];
+        // Never write something that does not decode to the same conditions
+        if AccessBits::from_bytes(bytes) == Some(*self) {
+            Some(bytes)
+        } else {
+            None
+        }
+    }
+
+    /// Decode the bytes 6 to 8 of the trailer. None if the inverted bits don't match.
+    pub fn from_bytes(bytes: [u8; 3]) -> Option<Self> {
+        let c1 = bytes[1] >> 4;
+        let c2 = bytes[2] & 0x0F;
+        let c3 = bytes[2] >> 4;
+        if bytes[0] & 0x0F != !c1 & 0x0F
+            || bytes[0] >> 4 != !c2 & 0x0F
+            || bytes[1] & 0x0F != !c3 & 0x0F
+        {
+            return None;
+        }
+        let mut conditions = [0; 4];
+        for (index, condition) in conditions.iter_mut().enumerate() {
+            let bit = |nibble: u8| (nibble >> index) & 1;
+            *condition = (bit(c1) << 2) | (bit(c2) << 1) | bit(c3);
+        }
+        Some(Self { conditions })
+    }
+
+    /// C1, C2 and C3 of all blocks, bit n is the block n
+    fn nibbles(&self) -> (u8, u8, u8) {
+        self.conditions
+            .iter()
+            .enumerate()
+            .fold((0, 0, 0), |(c1, c2, c3), (index, condition)| {
+                (
+                    c1 | ((condition >> 2) & 1) << index,
+                    c2 | ((condition >> 1) & 1) << index,
+                    c3 | (condition & 1) << index,
+                )
+            })
+    }
+}
+
+/// Steps of writing a card that can fail
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum WriteError {
+    /// The tag did not answer
+    Select,
+    /// The key was not accepted
+    Authenticate,
+    /// The block could not be written
+    Write,
+    /// The block read back differs from the written data
+    Verify,
+    /// The sector trailer could not be changed safely
+    Protect,
+}
+
+/// Failures of reading tags and of the MFRC522 itself
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TagReaderError {
+    /// The MFRC522 does not answer on the SPI or is not configured any more
+    Spi,
+    /// The tag did not answer in time
+    Timeout,
+    /// No key was accepted by the tag
+    Authentication,
+    /// The CRC of the answer of the tag is wrong
+    Crc,
+    /// The answer of the tag is broken in another way (collision, parity, incomplete frame)
+    Protocol,
+    /// The data on the tag is not from a music box
+    BadCookie,
+    /// The tag is neither a MIFARE Classic nor a NTAG21x or is written with a newer card layout
+    UnsupportedCard,
+}
+
+impl<E> From<mfrc522::Error<E>> for TagReaderError {
+    fn from(error: mfrc522::Error<E>) -> Self {
+        match error {
+            mfrc522::Error::Spi(_) => TagReaderError::Spi,
+            mfrc522::Error::Timeout => TagReaderError::Timeout,
+            mfrc522::Error::Crc => TagReaderError::Crc,
+            _ => TagReaderError::Protocol,
+        }
+    }
+}
+
+/// Number of failures of each kind since the start
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct ErrorCounters {
+    pub spi: u16,
+    pub timeout: u16,
+    pub authentication: u16,
+    pub crc: u16,
+    pub protocol: u16,
+    pub bad_cookie: u16,
+    pub unsupported_card: u16,
+    /// Soft resets of the MFRC522 after repeated failures
+    pub recoveries: u16,
+}
+
+impl ErrorCounters {
+    fn count(&mut self, error: TagReaderError) {
+        let counter = match error {
+            TagReaderError::Spi => &mut self.spi,
+            TagReaderError::Timeout => &mut self.timeout,
+            TagReaderError::Authentication => &mut self.authentication,
+            TagReaderError::Crc => &mut self.crc,
+            TagReaderError::Protocol => &mut self.protocol,
+            TagReaderError::BadCookie => &mut self.bad_cookie,
+            TagReaderError::UnsupportedCard => &mut self.unsupported_card,
+        };
+        *counter = counter.saturating_add(1);
+    }
+}
+
+/// Default number of polls without the tag until it counts as removed
+const DEFAULT_REMOVAL_POLLS: u8 = 2;
+/// Failures of the MFRC522 in a row until it is reset
+const RECOVERY_FAILURES: u8 = 3;
+/// Versions of the MFRC522 and its clones that are accepted
+const KNOWN_VERSIONS: [u8; 4] = [0x91, 0x92, 0x88, 0x12];
+/// Register reads until the soft reset has to be finished
+const RESET_POLLS: u8 = 100;
+/// Register reads until the self-test has to be finished
+const SELF_TEST_POLLS: u8 = 100;
+/// The self-test fills the FIFO with 64 bytes
+const SELF_TEST_SIZE: usize = 64;
+/// Size of the internal buffer that is cleared before the self-test
+const INTERNAL_BUFFER_SIZE: usize = 25;
+
+/// Results of the self-test from the datasheet of the MFRC522 version 1.0 (0x91)
+const SELF_TEST_V1: [u8; SELF_TEST_SIZE] = [
+    0x00, 0xC6, 0x37, 0xD5, 0x32, 0xB7, 0x57, 0x5C, 0xC2, 0xD8, 0x7C, 0x4D, 0xD9, 0x70, 0xC7, 0x73,
+    0x10, 0xE6, 0xD2, 0xAA, 0x5E, 0xA1, 0x3E, 0x5A, 0x14, 0xAF, 0x30, 0x61, 0xC9, 0x70, 0xDB, 0x2E,
+    0x64, 0x22, 0x72, 0xB5, 0xBD, 0x65, 0xF4, 0xEC, 0x22, 0xBC, 0xD3, 0x72, 0x35, 0xCD, 0xAA, 0x41,
+    0x1F, 0xA7, 0xF3, 0x53, 0x14, 0xDE, 0x7E, 0x02, 0xD9, 0x0F, 0xB5, 0x5E, 0x25, 0x1D, 0x29, 0x79,
+];
+/// Results of the self-test from the datasheet of the MFRC522 version 2.0 (0x92)
+const SELF_TEST_V2: [u8; SELF_TEST_SIZE] = [
+    0x00, 0xEB, 0x66, 0xBA, 0x57, 0xBF, 0x23, 0x95, 0xD0, 0xE3, 0x0D, 0x3D, 0x27, 0x89, 0x5C, 0xDE,
+    0x9D, 0x3B, 0xA7, 0x00, 0x21, 0x5B, 0x89, 0x82, 0x51, 0x3A, 0xEB, 0x02, 0x0C, 0xA5, 0x00, 0x49,
+    0x7C, 0x84, 0x4D, 0xB3, 0xCC, 0xD2, 0x1B, 0x81, 0x5D, 0x48, 0x76, 0xD5, 0x71, 0x61, 0x21, 0xA9,
+    0x86, 0x96, 0x83, 0x38, 0xCF, 0x9D, 0x5B, 0x6D, 0xDC, 0x15, 0xBA, 0x3E, 0x7D, 0x95, 0x3B, 0x2F,
+];
+/// Results of the self-test of the FM17522 clone (0x88)
+const SELF_TEST_FM17522: [u8; SELF_TEST_SIZE] = [
+    0x00, 0xD6, 0x78, 0x8C, 0xE2, 0xAA, 0x0C, 0x18, 0x2A, 0xB8, 0x7A, 0x7F, 0xD3, 0x6A, 0xCF, 0x0B,
+    0xB1, 0x37, 0x63, 0x4B, 0x69, 0xAE, 0x91, 0xC7, 0xC3, 0x97, 0xAE, 0x77, 0xF4, 0x37, 0xD7, 0x9B,
+    0x7C, 0xF5, 0x3C, 0x11, 0x8F, 0x15, 0xC3, 0xD7, 0xC1, 0x5B, 0x00, 0x2A, 0xD0, 0x75, 0xDE, 0x9E,
+    0x51, 0x64, 0xAB, 0x3E, 0xE9, 0x15, 0xB5, 0xAB, 0x56, 0x9A, 0x98, 0x82, 0x26, 0xEA, 0x2A, 0x62,
+];
+
+/// Outcome of the digital self-test of the MFRC522 at startup
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SelfTest {
+    /// The result matches the reference of the version
+    Passed(u8),
+    /// The result differs from the reference or the test did not finish
+    Failed(u8),
+    /// There is no reference for the version, e.g. for the counterfeit chips with 0x12
+    Untested(u8),
+    /// The MFRC522 does not answer with a known version
+    NoAnswer,
+}
+
+impl SelfTest {
+    /// The reader can be used
+    pub fn is_ok(&self) -> bool {
+        match self {
+            SelfTest::Passed(_) | SelfTest::Untested(_) => true,
+            SelfTest::Failed(_) | SelfTest::NoAnswer => false,
+        }
+    }
+}
+
+/// Gain of the receiver. A higher gain reads tags from further away, but is more sensitive to
+/// noise.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AntennaGain {
+    Db18 = 0b000,
+    Db23 = 0b001,
+    /// Default of the MFRC522
+    Db33 = 0b100,
+    Db38 = 0b101,
+    Db43 = 0b110,
+    Db48 = 0b111,
+}
+
+/// Registers and commands of the MFRC522 that are used for the detection of tags by the IRQ pin
+/// and the recovery
+mod register {
+    pub const COMMAND: u8 = 0x01;
+    pub const COM_IEN: u8 = 0x02;
+    pub const DIV_IEN: u8 = 0x03;
+    pub const COM_IRQ: u8 = 0x04;
+    pub const FIFO_DATA: u8 = 0x09;
+    pub const FIFO_LEVEL: u8 = 0x0A;
+    pub const BIT_FRAMING: u8 = 0x0D;
+
+    pub const MODE: u8 = 0x11;
+    pub const TX_MODE: u8 = 0x12;
+    pub const RX_MODE: u8 = 0x13;
+    pub const TX_CONTROL: u8 = 0x14;
+    pub const TX_ASK: u8 = 0x15;
+    pub const MOD_WIDTH: u8 = 0x24;
+    pub const T_MODE: u8 = 0x2A;
+    pub const T_PRESCALER: u8 = 0x2B;
+    pub const T_RELOAD_HIGH: u8 = 0x2C;
+    pub const T_RELOAD_LOW: u8 = 0x2D;
+    pub const RF_CFG: u8 = 0x26;
+    pub const AUTO_TEST: u8 = 0x36;
+
+    pub const CMD_IDLE: u8 = 0x00;
+    pub const CMD_MEM: u8 = 0x01;
+    pub const CMD_CALC_CRC: u8 = 0x03;
+    pub const CMD_TRANSCEIVE: u8 = 0x0C;
+    pub const CMD_SOFT_RESET: u8 = 0x0F;
+
+    /// Set while the MFRC522 is in power down, e.g. during the soft reset
+    pub const POWER_DOWN: u8 = 0x10;
+    /// Only bit 7 is set in the TX control register without the antenna drivers
+    pub const TX_CONTROL_OFF: u8 = 0x80;
+    /// Both antenna drivers are enabled
+    pub const ANTENNA_ON: u8 = 0x03;
+    /// Bits of the receiver gain in the RF configuration
+    pub const RX_GAIN_MASK: u8 = 0x70;
+    /// The next CRC calculation runs the digital self-test
+    pub const SELF_TEST_ENABLE: u8 = 0x09;
+
+    /// The IRQ pin is low while an interrupt is pending
+    pub const IRQ_INV: u8 = 0x80;
+    /// Interrupt when a response has been received
+    pub const RX_IEN: u8 = 0x20;
+    /// Interrupt when the timer expired without a response
+    pub const TIMER_IEN: u8 = 0x01;
+    /// A response has been received
+    pub const RX_IRQ: u8 = 0x20;
+    /// Drive the IRQ pin push pull instead of open drain
+    pub const IRQ_PUSH_PULL: u8 = 0x80;
+    /// Writing zeros clears all interrupt flags
+    pub const CLEAR_IRQS: u8 = 0x7F;
+    pub const FLUSH_FIFO: u8 = 0x80;
+    /// The WUPA is a short frame with 7 bits
+    pub const SHORT_FRAME: u8 = 0x07;
+    pub const START_SEND: u8 = 0x80;
+
+    /// The timer limits the wait for an answer to 25 ms, a tick is 25 us with the prescaler
+    pub const TIMER_RELOAD: u16 = 1000;
+    /// A tag answers the WUPA within 1 ms
+    pub const ARM_TIMER_RELOAD: u16 = 40;
+
+    pub const PICC_WUPA: u8 = 0x52;
+}
+
+/// Change of the tag in the field
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TagChange {
+    /// A tag that differs from the last one is in the field
+    New(mfrc522::Uid),
+    /// The tag has left the field
+    Removed,
+}
+
+/// Reads and writes tags with a MFRC522 on any SPI with a chip select pin
+pub struct TagReader<SPI, CS> {
+    device: mfrc522::Mfrc522<SPI, CS>,
+    last_tag: Option<mfrc522::Uid>,
+    /// Type of the last tag from its ATQA, None if the ATQA is ambiguous
+    tag_type: Option<TagType>,
+    /// Polls in a row without a tag
+    missed_polls: u8,
+    /// Polls without a tag until it counts as removed
+    removal_polls: u8,
+    /// Block with the card data on MIFARE Classic tags
+    block: u8,
+    /// Keys that are tried for the authentication
+    keys: heapless::Vec<CardKey, MaxKeys>,
+    /// Index of the key that is tried first
+    preferred_key: usize,
+    /// Key B of protected cards, it is needed to write them
+    admin_key: Option<CardKey>,
+    /// Failures since the start
+    errors: ErrorCounters,
+    /// Failures of the MFRC522 in a row
+    reader_failures: u8,
+    /// Gain of the receiver, it is restored after a reset
+    antenna_gain: AntennaGain,
+    /// Outcome of the self-test at startup
+    self_test: SelfTest,
+    /// The detection by the IRQ pin is running
+    armed: bool,
+    /// The MFRC522 is in soft power down
+    powered_down: bool,
+}
+
+impl<SPI, CS, E> TagReader<SPI, CS>
+where
+    SPI: embedded_hal::blocking::spi::Transfer<u8, Error = E>
+        + embedded_hal::blocking::spi::Write<u8, Error = E>,
+    CS: embedded_hal::digital::v2::OutputPin,
+{
+    /// Use the MFRC522 on the SPI with the chip select pin. It is reset and configured by the
+    /// driver.
+    pub fn new(spi: SPI, cs: CS) -> Result<Self, TagReaderError> {
+        let device = mfrc522::Mfrc522::new(spi, cs)?;
+        let mut keys = heapless::Vec::new();
+        keys.push(CardKey::DEFAULT).ok();
+
+        let mut reader = Self {
+            device,
+            last_tag: None,
+            tag_type: None,
+            missed_polls: 0,
+            removal_polls: DEFAULT_REMOVAL_POLLS,
+            block: DEFAULT_CARD_BLOCK,
+            keys,
+            preferred_key: 0,
+            admin_key: None,
+            errors: ErrorCounters::default(),
+            reader_failures: 0,
+            antenna_gain: AntennaGain::Db33,
+            self_test: SelfTest::NoAnswer,
+            armed: false,
+            powered_down: false,
+        };
+
+        // A failed self-test or a missing MFRC522 is not fatal, it is reported by the
+        // application and reset later like after other failures
+        reader.self_test = reader.run_self_test();
+        reader.check_health().ok();
+
+        Ok(reader)
+    }
+
+    /// Block with the card data on MIFARE Classic tags. NTAG21x tags always use the pages 4
+    /// to 7. Blocks that can not hold data are ignored.
+    pub fn set_card_block(&mut self, block: u8) {
+        if is_data_block(block) {
+            self.block = block;
+        }
+    }
+
+    /// Keys that are tried in the given order for the authentication. Keys that don't fit
+    /// into the list are ignored.
+    pub fn set_keys(&mut self, keys: &[CardKey]) {
+        self.keys = heapless::Vec::new();
+        for key in keys.iter() {
+            self.keys.push(*key).ok();
+        }
+    }
+
+    /// Try the key with the given index in the list first
+    pub fn set_preferred_key(&mut self, index: usize) {
+        self.preferred_key = index;
+    }
+
+    /// Key B that protects cards against rewriting. Without it cards can not be protected.
+    pub fn set_admin_key(&mut self, bytes: Option<[u8; 6]>) {
+        self.admin_key = bytes.map(|bytes| CardKey::new(KeyType::B, bytes));
+    }
+
+    /// Number of polls without the tag until it counts as removed. A single failed poll does
+    /// not remove the tag, so it is not reported as new again afterwards.
+    pub fn set_removal_polls(&mut self, polls: u8) {
+        self.removal_polls = polls.max(1);
+    }
+
+    /// A tag has been detected and not removed yet
+    pub fn has_tag(&self) -> bool {
+        self.last_tag.is_some()
+    }
+
+    /// Send a single WUPA without waiting for the answer. The MFRC522 is woken up from the
+    /// power down for it. The IRQ pin goes low when a tag answers or when the timer of the
+    /// MFRC522 expires after 1 ms, `disarm_detection` tells which of them.
+    pub fn arm_detection(&mut self) {
+        use register::*;
+        // Nothing is detected without the configuration of the MFRC522
+        if self.check_health().is_err() {
+            return;
+        }
+        let reload = ARM_TIMER_RELOAD.to_be_bytes();
+        // The timer is restored by the disarm, also if arming fails halfway
+        self.armed = true;
+        let result = self.write_registers(&[
+            (COMMAND, CMD_IDLE),
+            (COM_IRQ, CLEAR_IRQS),
+            (T_RELOAD_HIGH, reload[0]),
+            (T_RELOAD_LOW, reload[1]),
+            (DIV_IEN, IRQ_PUSH_PULL),
+            (COM_IEN, IRQ_INV | RX_IEN | TIMER_IEN),
+            (FIFO_LEVEL, FLUSH_FIFO),
+            (FIFO_DATA, PICC_WUPA),
+            (BIT_FRAMING, SHORT_FRAME),
+            (COMMAND, CMD_TRANSCEIVE),
+            (BIT_FRAMING, SHORT_FRAME | START_SEND),
+        ]);
+        if let Err(error) = result {
+            self.failed(error);
+        }
+    }
+
+    /// Release the IRQ pin after a detection and restore the timer for the other commands.
+    /// Returns if a tag answered the WUPA.
+    pub fn disarm_detection(&mut self) -> bool {
+        self.disarm().unwrap_or_else(|error| {
+            self.failed(error);
+            false
+        })
+    }
+
+    /// Soft power down of the MFRC522 with the antenna off, e.g. when no tag answered the
+    /// detection. The registers keep their values, the next command wakes it up again.
+    pub fn power_down(&mut self) {
+        use register::*;
+        if self.powered_down {
+            return;
+        }
+        let result = self.ready().and_then(|_| {
+            self.write_registers(&[
+                (TX_CONTROL, TX_CONTROL_OFF),
+                (COMMAND, CMD_IDLE | POWER_DOWN),
+            ])
+        });
+        match result {
+            Ok(()) => self.powered_down = true,
+            Err(error) => {
+                self.failed(error);
+            }
+        }
+    }
+
+    /// Outcome of the self-test at startup
+    pub fn self_test(&self) -> SelfTest {
+        self.self_test
+    }
+
+    /// Gain of the receiver, it is kept after a reset of the MFRC522
+    pub fn set_antenna_gain(&mut self, gain: AntennaGain) {
+        self.antenna_gain = gain;
+        if let Err(error) = self.write_antenna_gain() {
+            self.failed(error);
+        }
+    }
+
+    /// Failures since the start
+    pub fn error_counters(&self) -> &ErrorCounters {
+        &self.errors
+    }
+
+    /// Check that the MFRC522 answers with a known version and its antenna is on. A reset
+    /// by a voltage drop turns the antenna off, the reader is deaf afterwards.
+    /// The MFRC522 is reset after repeated failures.
+    pub fn check_health(&mut self) -> Result<(), TagReaderError> {
+        // The antenna is off while the MFRC522 is powered down
+        if self.ready().is_err() {
+            return Err(self.failed(TagReaderError::Spi));
+        }
+        let version = self.device.version().map_err(|_| TagReaderError::Spi);
+        let healthy = version
+            .and_then(|version| {
+                self.read_register(register::TX_CONTROL)
+                    .map(|tx| (version, tx))
+            })
+            .map(|(version, tx)| {
+                KNOWN_VERSIONS.contains(&version)
+                    && tx & register::ANTENNA_ON == register::ANTENNA_ON
+            });
+        match healthy {
+            Ok(true) => {
+                self.reader_failures = 0;
+                Ok(())
+            }
+            _ => Err(self.failed(TagReaderError::Spi)),
+        }
+    }
+
+    /// Check if a tag is in the field
+    /// Returns the change if a new tag is in the field or the last tag has been removed
+    pub fn check_for_tag(&mut self) -> Option<TagChange> {
+        let uid = match self.select_tag() {
+            Ok((uid, tag_type)) => {
+                self.tag_type = tag_type;
+                Some(uid)
+            }
+            // No tag in the field, but the MFRC522 may be deaf
+            Err(TagReaderError::Timeout) => {
+                self.check_health().ok();
+                None
+            }
+            Err(error) => {
+                self.failed(error);
+                None
+            }
+        };
+
+        match uid {
+            Some(uid) => {
+                self.missed_polls = 0;
+                if self.last_tag.replace(uid) != Some(uid) {
+                    Some(TagChange::New(uid))
+                } else {
+                    None
+                }
+            }
+            None if self.last_tag.is_some() => {
+                self.missed_polls = self.missed_polls.saturating_add(1);
+                if self.missed_polls >= self.removal_polls {
+                    self.last_tag = None;
+                    Some(TagChange::Removed)
+                } else {
+                    None
+                }
+            }
+            None => None,
+        }
+    }
+
+    /// Read the card with the given uid
+    pub fn read_card(&mut self, uid: mfrc522::Uid) -> Result<Card, TagReaderError> {
+        let data = match self.detected_type(&uid) {
+            Some(tag_type) => self.read_data(&uid, tag_type),
+            None => self.read_any_type(&uid),
+        };
+        let result = data.and_then(|data| {
+            let card = Card::try_from(data).map_err(|_| TagReaderError::BadCookie)?;
+            if card.is_supported() {
+                Ok(card)
+            } else {
+                Err(TagReaderError::UnsupportedCard)
+            }
+        });
+
+        result.map_err(|error| self.failed(error))
+    }
+
+    /// Read a tag with an ambiguous ATQA, the other type is tried if the guess is wrong
+    fn read_any_type(&mut self, uid: &mfrc522::Uid) -> Result<[u8; 16], TagReaderError> {
+        let tag_type = TagType::guess(uid);
+        self.read_data(uid, tag_type).or_else(|first| {
+            self.read_data(uid, tag_type.other())
+                .map_err(|second| match (first, second) {
+                    (TagReaderError::Spi, _) | (_, TagReaderError::Spi) => TagReaderError::Spi,
+                    // The guess was right, but the key is not known
+                    (TagReaderError::Authentication, _) | (_, TagReaderError::Authentication) => {
+                        TagReaderError::Authentication
+                    }
+                    (TagReaderError::Crc, _) | (_, TagReaderError::Crc) => TagReaderError::Crc,
+                    // Neither the MIFARE Classic nor the NTAG commands are understood
+                    _ => TagReaderError::UnsupportedCard,
+                })
+        })
+    }
+
+    /// Type of the tag from its ATQA, if it is the last detected tag
+    fn detected_type(&self, uid: &mfrc522::Uid) -> Option<TagType> {
+        self.tag_type.filter(|_| self.last_tag == Some(*uid))
+    }
+
+    /// Count the failure. Repeated failures of the MFRC522 itself reset it.
+    fn failed(&mut self, error: TagReaderError) -> TagReaderError {
+        self.errors.count(error);
+        if error == TagReaderError::Spi {
+            self.reader_failures = self.reader_failures.saturating_add(1);
+            if self.reader_failures >= RECOVERY_FAILURES {
+                self.recover();
+            }
+        }
+        error
+    }
+
+    /// Soft reset of the MFRC522 with the configuration of the driver. A failed reset is
+    /// counted by the next failure.
+    fn recover(&mut self) {
+        self.reader_failures = 0;
+        self.errors.recoveries = self.errors.recoveries.saturating_add(1);
+        self.soft_reset().and_then(|_| self.configure()).ok();
+    }
+
+    fn soft_reset(&mut self) -> Result<(), TagReaderError> {
+        use register::*;
+        self.write_register(COMMAND, CMD_SOFT_RESET)?;
+        self.wait_for_oscillator()?;
+        // The reset ends the detection and the power down
+        self.armed = false;
+        self.powered_down = false;
+        Ok(())
+    }
+
+    /// The oscillator has to start again after a reset or the power down, the MFRC522 is
+    /// ready when the power down bit is cleared
+    fn wait_for_oscillator(&mut self) -> Result<(), TagReaderError> {
+        use register::*;
+        for _ in 0..RESET_POLLS {
+            if self.read_register(COMMAND)? & POWER_DOWN == 0 {
+                return Ok(());
+            }
+        }
+        Err(TagReaderError::Timeout)
+    }
+
+    /// End a running detection and the power down before other commands
+    fn ready(&mut self) -> Result<(), TagReaderError> {
+        use register::*;
+        if self.armed {
+            self.disarm()?;
+        }
+        if self.powered_down {
+            self.write_register(COMMAND, CMD_IDLE)?;
+            self.wait_for_oscillator()?;
+            self.write_register(TX_CONTROL, TX_CONTROL_OFF | ANTENNA_ON)?;
+            self.powered_down = false;
+        }
+        Ok(())
+    }
+
+    /// Stop the detection, the timer is set back for the answers of the other commands
+    fn disarm(&mut self) -> Result<bool, TagReaderError> {
+        use register::*;
+        let answered = self.read_register(COM_IRQ)? & RX_IRQ != 0;
+        let reload = TIMER_RELOAD.to_be_bytes();
+        self.write_registers(&[
+            (COM_IEN, IRQ_INV),
+            (COMMAND, CMD_IDLE),
+            (COM_IRQ, CLEAR_IRQS),
+            (T_RELOAD_HIGH, reload[0]),
+            (T_RELOAD_LOW, reload[1]),
+        ])?;
+        self.armed = false;
+        Ok(answered)
+    }
+
+    /// Configuration of the driver after a reset
+    fn configure(&mut self) -> Result<(), TagReaderError> {
+        use register::*;
+        let reload = TIMER_RELOAD.to_be_bytes();
+        self.write_registers(&[
+            (TX_MODE, 0x00),
+            (RX_MODE, 0x00),
+            (MOD_WIDTH, 0x26),
+            // The timer starts after sending and limits the wait for an answer to 25 ms
+            (T_MODE, 0x80),
+            (T_PRESCALER, 0xA9),
+            (T_RELOAD_HIGH, reload[0]),
+            (T_RELOAD_LOW, reload[1]),
+            // 100% ASK modulation and CRC preset 0x6363
+            (TX_ASK, 0x40),
+            (MODE, 0x3D),
+            (TX_CONTROL, TX_CONTROL_OFF | ANTENNA_ON),
+        ])?;
+        self.write_antenna_gain()
+    }
+
+    fn write_antenna_gain(&mut self) -> Result<(), TagReaderError> {
+        use register::*;
+        let rf_cfg = self.read_register(RF_CFG)? & !RX_GAIN_MASK;
+        self.write_register(RF_CFG, rf_cfg | ((self.antenna_gain as u8) << 4))
+    }
+
+    /// Run the digital self-test and compare the result with the reference of the version.
+    /// The MFRC522 is reset and configured again afterwards.
+    fn run_self_test(&mut self) -> SelfTest {
+        let version = match self.device.version() {
+            Ok(version) if KNOWN_VERSIONS.contains(&version) => version,
+            _ => return SelfTest::NoAnswer,
+        };
+        let reference = match version {
+            0x91 => Some(&SELF_TEST_V1),
+            0x92 => Some(&SELF_TEST_V2),
+            0x88 => Some(&SELF_TEST_FM17522),
+            _ => None,
+        };
+
+        let result = reference.map(|_| self.read_self_test());
+        // Versions without a reference get the same configuration as the tested ones
+        let configured = self
+            .write_register(register::AUTO_TEST, 0x00)
+            .and_then(|_| self.soft_reset())
+            .and_then(|_| self.configure());
+        match (reference, result, configured) {
+            (None, _, _) => SelfTest::Untested(version),
+            (Some(reference), Some(Ok(result)), Ok(())) if result[..] == reference[..] => {
+                SelfTest::Passed(version)
+            }
+            _ => SelfTest::Failed(version),
+        }
+    }
+
+    /// Steps of the self-test from the datasheet
+    fn read_self_test(&mut self) -> Result<[u8; SELF_TEST_SIZE], TagReaderError> {
+        use register::*;
+        self.soft_reset()?;
+
+        // Clear the internal buffer
+        self.write_register(FIFO_LEVEL, FLUSH_FIFO)?;
+        for _ in 0..INTERNAL_BUFFER_SIZE {
+            self.write_register(FIFO_DATA, 0x00)?;
+        }
+        self.write_register(COMMAND, CMD_MEM)?;
+
+        // The CRC calculation of a single byte runs the test
+        self.write_registers(&[
+            (AUTO_TEST, SELF_TEST_ENABLE),
+            (FIFO_DATA, 0x00),
+            (COMMAND, CMD_CALC_CRC),
+        ])?;
+        let mut finished = false;
+        for _ in 0..SELF_TEST_POLLS {
+            if usize::from(self.read_register(FIFO_LEVEL)?) >= SELF_TEST_SIZE {
+                finished = true;
+                break;
+            }
+        }
+        self.write_register(COMMAND, CMD_IDLE)?;
+        if !finished {
+            return Err(TagReaderError::Timeout);
+        }
+
+        let mut result = [0; SELF_TEST_SIZE];
+        for byte in result.iter_mut() {
+            *byte = self.read_register(FIFO_DATA)?;
+        }
+        Ok(result)
+    }
+
+    fn read_register(&mut self, register: u8) -> Result<u8, TagReaderError> {
+        self.device
+            .read_register(register)
+            .map_err(|_| TagReaderError::Spi)
+    }
+
+    fn write_register(&mut self, register: u8, value: u8) -> Result<(), TagReaderError> {
+        self.device
+            .write_register(register, value)
+            .map_err(|_| TagReaderError::Spi)
+    }
+
+    fn write_registers(&mut self, values: &[(u8, u8)]) -> Result<(), TagReaderError> {
+        values
+            .iter()
+            .try_for_each(|(register, value)| self.write_register(*register, *value))
+    }
+
+    /// Select a tag in the field and send it to HALT state. The type is known from the ATQA
+    /// unless it is ambiguous.
+    fn select_tag(&mut self) -> Result<(mfrc522::Uid, Option<TagType>), TagReaderError> {
+        self.ready()?;
+        let atqa = self.device.wupa()?;
+        let uid = self.device.select(&atqa)?;
+        self.device.hlta()?;
+        Ok((uid, TagType::from_atqa(&atqa)))
+    }
+
+    /// Wakeup the card in the field and select it
+    fn wakeup(&mut self) -> Result<(), TagReaderError> {
+        self.ready()?;
+        let atqa = self.device.wupa()?;
+        self.device.select(&atqa)?;
+        Ok(())
+    }
+
+    /// Key A of protected cards, the preferred key is used to read them
+    fn box_key(&self) -> [u8; 6] {
+        self.keys
+            .get(self.preferred_key)
+            .unwrap_or(&CardKey::DEFAULT)
+            .bytes
+    }
+
+    /// Start the crypto session for the card block. The preferred key is tried first, then
+    /// the others in the order of the list. For writing the admin key is tried before them.
+    /// A failed authentication ends the selection of the card, so it is selected again for
+    /// the next key.
+    fn authenticate(&mut self, uid: &mfrc522::Uid, write: bool) -> Result<(), TagReaderError> {
+        let keys = self.keys.clone();
+        let admin = self.admin_key.filter(|_| write);
+        let preferred_key = self.preferred_key;
+        let preferred = keys.get(preferred_key).copied();
+        let others = keys
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| *index != preferred_key)
+            .map(|(_, key)| *key);
+
+        let candidates = admin.into_iter().chain(preferred).chain(others);
+        for (attempt, key) in candidates.enumerate() {
+            if attempt > 0 {
+                self.wakeup()?;
+            }
+            let result = self
+                .device
+                .mfauthent(self.block, uid, &key.mifare_key())
+                .map_err(TagReaderError::from);
+            match result {
+                Ok(_) => return Ok(()),
+                Err(TagReaderError::Spi) => return Err(TagReaderError::Spi),
+                // Try the next key
+                Err(_) => {}
+            }
+        }
+        Err(TagReaderError::Authentication)
+    }
+
+    /// Read the 16 bytes of card data
+    fn read_data(
+        &mut self,
+        uid: &mfrc522::Uid,
+        tag_type: TagType,
+    ) -> Result<[u8; 16], TagReaderError> {
+        // Wakeup card
+        self.wakeup()?;
+
+        let data = match tag_type {
+            TagType::MifareClassic => {
+                // Try to start crypto
+                self.authenticate(uid, false)?;
+
+                // Read the data from the card
+                let data = self.device.mfread(self.block).map_err(TagReaderError::from);
+
+                // End crypto session
+                self.device.mfstopcrypto().ok();
+                data
+            }
+            // The read command returns four pages at once
+            TagType::Ntag => self
+                .device
+                .mfread(NTAG_FIRST_PAGE)
+                .map_err(TagReaderError::from),
+        };
+
+        // Send to sleep state
+        self.device.hlta().ok();
+
+        data
+    }
+
+    /// Write the card to the tag with the given uid. The data is read back to verify it.
+    /// Protected MIFARE Classic cards can only be rewritten with the admin key, other boxes
+    /// can still read them.
+    pub fn write_card(
+        &mut self,
+        uid: mfrc522::Uid,
+        card: Card,
+        protect: bool,
+    ) -> Result<(), WriteError> {
+        let data = <[u8; 16]>::from(card);
+        if let Some(tag_type) = self.detected_type(&uid) {
+            return self.write_data(&uid, tag_type, data, protect);
+        }
+        let tag_type = TagType::guess(&uid);
+        match self.write_data(&uid, tag_type, data, protect) {
+            // Maybe the guess was wrong, the other tag type rejects it the same way
+            Err(WriteError::Authenticate) | Err(WriteError::Write) => {
+                self.write_data(&uid, tag_type.other(), data, protect)
+            }
+            result => result,
+        }
+    }
+
+    /// Reset the sector of a protected MIFARE Classic card to the transport keys and access
+    /// bits of new cards
+    pub fn unlock_card(&mut self, uid: mfrc522::Uid) -> Result<(), WriteError> {
+        if self.wakeup().is_err() {
+            return Err(WriteError::Select);
+        }
+        if self.authenticate(&uid, true).is_err() {
+            self.device.hlta().ok();
+            return Err(WriteError::Authenticate);
+        }
+        let result = self.write_trailer(
+            &uid,
+            CardKey::DEFAULT.bytes,
+            AccessBits::TRANSPORT,
+            CardKey::DEFAULT.bytes,
+        );
+        self.device.hlta().ok();
+        result
+    }
+
+    /// Write the 16 bytes of card data and read them back
+    fn write_data(
+        &mut self,
+        uid: &mfrc522::Uid,
+        tag_type: TagType,
+        data: [u8; 16],
+        protect: bool,
+    ) -> Result<(), WriteError> {
+        // Without the admin key the card can not be protected, so nothing is written
+        if tag_type == TagType::MifareClassic && protect && self.admin_key.is_none() {
+            return Err(WriteError::Protect);
+        }
+
+        // Wakeup card
+        if self.wakeup().is_err() {
+            return Err(WriteError::Select);
+        }
+
+        let result = match tag_type {
+            TagType::MifareClassic => {
+                let block = self.block;
+                let result = self
+                    .authenticate(uid, true)
+                    .map_err(|_| WriteError::Authenticate)
+                    .and_then(|_| {
+                        self.device
+                            .mfwrite(block, data)
+                            .map_err(|_| WriteError::Write)
+                    })
+                    .and_then(|_| self.verify(block, &data))
+                    .and_then(|_| match self.admin_key {
+                        Some(admin_key) if protect => {
+                            let box_key = self.box_key();
+                            self.write_trailer(uid, box_key, AccessBits::LOCKED, admin_key.bytes)
+                        }
+                        _ => Ok(()),
+                    });
+
+                // End crypto session on every path, also if writing failed
+                self.device.mfstopcrypto().ok();
+                result
+            }
+            TagType::Ntag => self.write_pages(&data),
+        };
+
+        // Send to sleep state
+        self.device.hlta().ok();
+
+        result
+    }
+
+    /// Write the data to four NTAG pages. The MIFARE write is accepted as compatibility
+    /// write, it only stores the first four bytes to the page.
+    fn write_pages(&mut self, data: &[u8; 16]) -> Result<(), WriteError> {
+        for (index, chunk) in data.chunks(NTAG_PAGE_SIZE).enumerate() {
+            let mut page = [0; 16];
+            page[..NTAG_PAGE_SIZE].copy_from_slice(chunk);
+            self.device
+                .mfwrite(NTAG_FIRST_PAGE + index as u8, page)
+                .map_err(|_| WriteError::Write)?;
+        }
+        self.verify(NTAG_FIRST_PAGE, data)
+    }
+
+    /// Rewrite the trailer of the sector with the card block in the running crypto session.
+    /// Keys can not be read back, so the access bits are checked with a new session with the
+    /// new key A.
+    fn write_trailer(
+        &mut self,
+        uid: &mfrc522::Uid,
+        key_a: [u8; 6],
+        access: AccessBits,
+        key_b: [u8; 6],
+    ) -> Result<(), WriteError> {
+        let result = self.write_trailer_block(key_a, access, key_b);
+        self.device.mfstopcrypto().ok();
+        result?;
+
+        let trailer = trailer_block(self.block).ok_or(WriteError::Protect)?;
+        let bits = access.to_bytes().ok_or(WriteError::Protect)?;
+        if self.wakeup().is_err() {
+            return Err(WriteError::Verify);
+        }
+        let key = CardKey::new(KeyType::A, key_a).mifare_key();
+        let read = self
+            .device
+            .mfauthent(trailer, uid, &key)
+            .and_then(|_| self.device.mfread(trailer));
+        self.device.mfstopcrypto().ok();
+        match read {
+            Ok(read) if read[6..9] == bits => Ok(()),
+            _ => Err(WriteError::Verify),
+        }
+    }
+
+    /// Write the trailer after all checks passed
+    fn write_trailer_block(
+        &mut self,
+        key_a: [u8; 6],
+        access: AccessBits,
+        key_b: [u8; 6],
+    ) -> Result<(), WriteError> {
+        let trailer = trailer_block(self.block).ok_or(WriteError::Protect)?;
+        let bits = access.to_bytes().ok_or(WriteError::Protect)?;
+
+        let mut data = [0; 16];
+        data[..6].copy_from_slice(&key_a);
+        data[6..9].copy_from_slice(&bits);
+        data[9] = TRAILER_GPB;
+        data[10..].copy_from_slice(&key_b);
+        self.device
+            .mfwrite(trailer, data)
+            .map_err(|_| WriteError::Protect)
+    }
+
+    /// Read back written data
+    fn verify(&mut self, block: u8, data: &[u8; 16]) -> Result<(), WriteError> {
+        match self.device.mfread(block) {
+            Ok(read) if read == *data => Ok(()),
+            _ => Err(WriteError::Verify),
+        }
+    }
+}
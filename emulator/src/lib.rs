@@ -0,0 +1,451 @@
+//! Emulation of a DFPlayer Mini on the host.
+//!
+//! The emulator implements the serial traits of `embedded-hal` that the firmware uses for the
+//! USART, so the DFPlayer driver and the application logic can be connected to it in tests.
+//! The answers to a command are available as soon as its frame is written. Playback time
+//! only passes when `advance` is called.
+//!
+//! The module `mfrc522` emulates the tag reader on the SPI with virtual tags in its field.
+
+use core::convert::Infallible;
+use embedded_hal::serial::{Read, Write};
+use std::collections::VecDeque;
+
+pub mod mfrc522;
+mod sdcard;
+mod tag;
+
+pub use sdcard::{SdCard, Track};
+pub use tag::{KeyType, Tag, TagKind};
+
+const FRAME_START: u8 = 0x7E;
+const FRAME_VERSION: u8 = 0xFF;
+const FRAME_LEN: u8 = 0x06;
+const FRAME_END: u8 = 0xEF;
+pub const FRAME_SIZE: usize = 10;
+
+/// Volume after power up and reset
+pub const DEFAULT_VOLUME: u8 = 30;
+pub const MAX_VOLUME: u8 = 30;
+/// Media flag of the SD card in notifications and the status answer
+pub const MEDIA_SD: u16 = 0x02;
+
+/// Error codes of the `0x40` notification
+pub const ERROR_SLEEPING: u16 = 0x02;
+pub const ERROR_CHECKSUM: u16 = 0x04;
+pub const ERROR_NOT_FOUND: u16 = 0x06;
+
+/// Default length of every track
+const DEFAULT_TRACK_MS: u32 = 3000;
+/// Default time from power up or reset until the online notification
+const DEFAULT_BOOT_MS: u32 = 1500;
+
+/// Checksum over version, length, command, ack and parameter bytes
+pub fn checksum(bytes: &[u8]) -> u16 {
+    bytes
+        .iter()
+        .fold(0_u16, |sum, byte| sum.wrapping_add(u16::from(*byte)))
+        .wrapping_neg()
+}
+
+/// Build a frame `7E FF 06 cmd ack p1 p2 chk_hi chk_lo EF`
+pub fn frame(cmd: u8, ack: bool, param: u16) -> [u8; FRAME_SIZE] {
+    let param = param.to_be_bytes();
+    let mut frame = [
+        FRAME_START,
+        FRAME_VERSION,
+        FRAME_LEN,
+        cmd,
+        ack as u8,
+        param[0],
+        param[1],
+        0,
+        0,
+        FRAME_END,
+    ];
+    let checksum = checksum(&frame[1..7]).to_be_bytes();
+    frame[7] = checksum[0];
+    frame[8] = checksum[1];
+    frame
+}
+
+/// Playback state as reported in the status answer
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PlayState {
+    Stopped = 0,
+    Playing = 1,
+    Paused = 2,
+}
+
+/// Virtual DFPlayer with a virtual SD card
+#[derive(Debug)]
+pub struct Emulator {
+    card: Option<SdCard>,
+    track_ms: u32,
+    boot_ms: u32,
+    /// Remaining time until the online notification, `None` when online
+    booting: Option<u32>,
+    /// Bytes of the frame that is received
+    input: Vec<u8>,
+    /// Bytes waiting to be read by the driver
+    output: VecDeque<u8>,
+    /// Command and parameter of every received frame
+    received: Vec<(u8, u16)>,
+    volume: u8,
+    equalizer: u8,
+    device: u8,
+    standby: bool,
+    state: PlayState,
+    track: Option<Track>,
+    remaining_ms: u32,
+    /// Track that continues after an advert
+    interrupted: Option<(Track, u32)>,
+    /// Number of the next received frames that are lost
+    lost_frames: u32,
+}
+
+impl Emulator {
+    /// Power up the emulator with an inserted SD card
+    pub fn new(card: SdCard) -> Self {
+        Self::with_card(Some(card))
+    }
+
+    /// Power up the emulator without a SD card
+    pub fn without_card() -> Self {
+        Self::with_card(None)
+    }
+
+    fn with_card(card: Option<SdCard>) -> Self {
+        Self {
+            card,
+            track_ms: DEFAULT_TRACK_MS,
+            boot_ms: DEFAULT_BOOT_MS,
+            booting: Some(DEFAULT_BOOT_MS),
+            input: Vec::new(),
+            output: VecDeque::new(),
+            received: Vec::new(),
+            volume: DEFAULT_VOLUME,
+            equalizer: 0,
+            device: MEDIA_SD as u8,
+            standby: false,
+            state: PlayState::Stopped,
+            track: None,
+            remaining_ms: 0,
+            interrupted: None,
+            lost_frames: 0,
+        }
+    }
+
+    /// Length of every track that is started from now on
+    pub fn set_track_ms(&mut self, track_ms: u32) {
+        self.track_ms = track_ms;
+    }
+
+    /// Time from a reset until the online notification
+    pub fn set_boot_ms(&mut self, boot_ms: u32) {
+        self.boot_ms = boot_ms;
+    }
+
+    /// The next `count` frames that are written are lost, e.g. because of a disturbed line
+    pub fn lose_frames(&mut self, count: u32) {
+        self.lost_frames = count;
+    }
+
+    /// Let time pass. Finished tracks and the end of the boot are notified.
+    pub fn advance(&mut self, ms: u32) {
+        if let Some(booting) = self.booting {
+            if booting > ms {
+                self.booting = Some(booting - ms);
+                return;
+            }
+            self.booting = None;
+            let media = self.media();
+            self.notify(0x3F, media);
+        }
+
+        if self.state != PlayState::Playing {
+            return;
+        }
+        if self.remaining_ms > ms {
+            self.remaining_ms -= ms;
+            return;
+        }
+        self.remaining_ms = 0;
+
+        match (self.track, self.interrupted.take()) {
+            // The interrupted track continues silently after an advert
+            (Some(Track::Advert(_)), Some((track, remaining_ms))) => {
+                self.track = Some(track);
+                self.remaining_ms = remaining_ms;
+            }
+            (Some(track), _) => {
+                self.state = PlayState::Stopped;
+                if let Some(number) = self.file_number(track) {
+                    self.notify(0x3D, number);
+                }
+            }
+            (None, _) => self.state = PlayState::Stopped,
+        }
+    }
+
+    /// Insert a SD card, the DFPlayer notifies it
+    pub fn insert_card(&mut self, card: SdCard) {
+        self.card = Some(card);
+        self.notify(0x3A, MEDIA_SD);
+    }
+
+    /// Remove the SD card, the playback stops
+    pub fn remove_card(&mut self) {
+        self.card = None;
+        self.stop();
+        self.notify(0x3B, MEDIA_SD);
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.booting.is_none()
+    }
+
+    pub fn volume(&self) -> u8 {
+        self.volume
+    }
+
+    pub fn equalizer(&self) -> u8 {
+        self.equalizer
+    }
+
+    pub fn device(&self) -> u8 {
+        self.device
+    }
+
+    pub fn is_standby(&self) -> bool {
+        self.standby
+    }
+
+    pub fn state(&self) -> PlayState {
+        self.state
+    }
+
+    /// Track that is playing or paused
+    pub fn track(&self) -> Option<Track> {
+        self.track
+    }
+
+    /// Command and parameter of all frames received so far
+    pub fn received(&self) -> &[(u8, u16)] {
+        &self.received
+    }
+
+    /// Command and parameter of the frames received so far. The list is cleared.
+    pub fn take_received(&mut self) -> Vec<(u8, u16)> {
+        core::mem::take(&mut self.received)
+    }
+
+    fn media(&self) -> u16 {
+        if self.card.is_some() {
+            MEDIA_SD
+        } else {
+            0
+        }
+    }
+
+    fn file_number(&self, track: Track) -> Option<u16> {
+        self.card.as_ref().and_then(|card| card.file_number(track))
+    }
+
+    fn notify(&mut self, cmd: u8, param: u16) {
+        self.output.extend(frame(cmd, false, param).iter());
+    }
+
+    fn stop(&mut self) {
+        self.state = PlayState::Stopped;
+        self.track = None;
+        self.remaining_ms = 0;
+        self.interrupted = None;
+    }
+
+    fn start(&mut self, track: Track) {
+        if matches!(&self.card, Some(card) if card.contains(track)) {
+            self.track = Some(track);
+            self.state = PlayState::Playing;
+            self.remaining_ms = self.track_ms;
+            self.interrupted = None;
+        } else {
+            self.notify(0x40, ERROR_NOT_FOUND);
+        }
+    }
+
+    fn start_number(&mut self, number: u16) {
+        match self.card.as_ref().and_then(|card| card.track(number)) {
+            Some(track) => self.start(track),
+            None => self.notify(0x40, ERROR_NOT_FOUND),
+        }
+    }
+
+    /// File number of the current track, the first file if nothing has been played
+    fn current_number(&self) -> u16 {
+        self.track
+            .and_then(|track| self.file_number(track))
+            .unwrap_or(0)
+    }
+
+    fn file_count(&self) -> u16 {
+        self.card.as_ref().map_or(0, |card| card.file_count())
+    }
+
+    /// Collect the received bytes. Bytes before a start byte are dropped.
+    fn receive(&mut self, byte: u8) {
+        if self.input.is_empty() && byte != FRAME_START {
+            return;
+        }
+        self.input.push(byte);
+        if self.input.len() < FRAME_SIZE {
+            return;
+        }
+
+        let bytes = core::mem::take(&mut self.input);
+        if self.lost_frames > 0 {
+            self.lost_frames -= 1;
+            return;
+        }
+        if bytes[1] != FRAME_VERSION || bytes[2] != FRAME_LEN || bytes[9] != FRAME_END {
+            return;
+        }
+        if checksum(&bytes[1..7]) != u16::from_be_bytes([bytes[7], bytes[8]]) {
+            self.notify(0x40, ERROR_CHECKSUM);
+            return;
+        }
+        self.execute(
+            bytes[3],
+            bytes[4] != 0,
+            u16::from_be_bytes([bytes[5], bytes[6]]),
+        );
+    }
+
+    fn execute(&mut self, cmd: u8, ack: bool, param: u16) {
+        // Nothing is received while booting
+        if !self.is_online() {
+            return;
+        }
+        self.received.push((cmd, param));
+        if ack {
+            self.notify(0x41, 0);
+        }
+        if self.standby && cmd != 0x0B && cmd != 0x0C {
+            self.notify(0x40, ERROR_SLEEPING);
+            return;
+        }
+
+        let [high, low] = param.to_be_bytes();
+        match cmd {
+            // Next and previous wrap around
+            0x01 => {
+                let count = self.file_count();
+                if count > 0 {
+                    self.start_number(self.current_number() % count + 1);
+                }
+            }
+            0x02 => {
+                let count = self.file_count();
+                if count > 0 {
+                    self.start_number((self.current_number() + count - 2) % count + 1);
+                }
+            }
+            0x03 => self.start_number(param),
+            0x04 => self.volume = (self.volume + 1).min(MAX_VOLUME),
+            0x05 => self.volume = self.volume.saturating_sub(1),
+            0x06 => self.volume = low.min(MAX_VOLUME),
+            0x07 => self.equalizer = low,
+            0x09 => self.device = low,
+            0x0A => {
+                self.stop();
+                self.standby = true;
+            }
+            0x0B => self.standby = false,
+            0x0C => {
+                self.stop();
+                self.volume = DEFAULT_VOLUME;
+                self.equalizer = 0;
+                self.device = MEDIA_SD as u8;
+                self.standby = false;
+                self.booting = Some(self.boot_ms);
+            }
+            0x0D if self.track.is_some() => {
+                if self.state == PlayState::Stopped {
+                    self.remaining_ms = self.track_ms;
+                }
+                self.state = PlayState::Playing;
+            }
+            0x0E if self.state == PlayState::Playing => self.state = PlayState::Paused,
+            0x0F => self.start(Track::Folder(high, low)),
+            0x12 => self.start(Track::Mp3(param)),
+            // Adverts are only played while a track is playing
+            0x13 if self.state == PlayState::Playing => {
+                let interrupted = self.interrupted.take().or_else(|| {
+                    self.track
+                        .filter(|track| !matches!(track, Track::Advert(_)))
+                        .map(|track| (track, self.remaining_ms))
+                });
+                self.start(Track::Advert(param));
+                if let Some(Track::Advert(_)) = self.track {
+                    self.interrupted = interrupted;
+                }
+            }
+            0x15 => {
+                if let Some((track, remaining_ms)) = self.interrupted.take() {
+                    self.track = Some(track);
+                    self.remaining_ms = remaining_ms;
+                }
+            }
+            0x16 => self.stop(),
+            0x42 => {
+                let status = (self.media() << 8) | self.state as u16;
+                self.notify(0x42, status)
+            }
+            0x43 => self.notify(0x43, u16::from(self.volume)),
+            0x44 => self.notify(0x44, u16::from(self.equalizer)),
+            0x48 => {
+                let count = self.file_count();
+                self.notify(0x48, count)
+            }
+            0x4C => {
+                let number = self.current_number();
+                self.notify(0x4C, number)
+            }
+            0x4E => match self
+                .card
+                .as_ref()
+                .and_then(|card| card.folder_track_count(low))
+            {
+                Some(tracks) => self.notify(0x4E, u16::from(tracks)),
+                None => self.notify(0x40, ERROR_NOT_FOUND),
+            },
+            0x4F => {
+                let count = self.card.as_ref().map_or(0, |card| card.folder_count());
+                self.notify(0x4F, count)
+            }
+            // Unknown commands are ignored like on the module
+            _ => (),
+        }
+    }
+}
+
+impl Read<u8> for Emulator {
+    type Error = Infallible;
+
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        self.output.pop_front().ok_or(nb::Error::WouldBlock)
+    }
+}
+
+impl Write<u8> for Emulator {
+    type Error = Infallible;
+
+    fn write(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
+        self.receive(byte);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        Ok(())
+    }
+}
@@ -0,0 +1,667 @@
+//! Register level emulation of a MFRC522 with a virtual tag in its field.
+//!
+//! The emulator implements the blocking SPI traits of `embedded-hal`, so the MFRC522 driver
+//! and the tag reader can be connected to it in tests. Commands and frames to the tag are
+//! processed as soon as they are started, the answer is in the FIFO before the driver polls
+//! the interrupt flags. Tags are placed and removed by the test, failures are scripted.
+
+use crate::tag::{sector_of, KeyType, Tag, TagKind};
+use core::convert::Infallible;
+use embedded_hal::blocking::spi::{Transfer, Write};
+use embedded_hal::digital::v2::OutputPin;
+use std::collections::VecDeque;
+
+/// Version of the emulated chip (MFRC522 2.0)
+pub const DEFAULT_VERSION: u8 = 0x92;
+
+mod register {
+    pub const COMMAND: u8 = 0x01;
+    pub const COM_IEN: u8 = 0x02;
+    pub const COM_IRQ: u8 = 0x04;
+    pub const DIV_IEN: u8 = 0x03;
+    pub const DIV_IRQ: u8 = 0x05;
+    pub const ERROR: u8 = 0x06;
+    pub const STATUS2: u8 = 0x08;
+    pub const FIFO_DATA: u8 = 0x09;
+    pub const FIFO_LEVEL: u8 = 0x0A;
+    pub const CONTROL: u8 = 0x0C;
+    pub const BIT_FRAMING: u8 = 0x0D;
+    pub const MODE: u8 = 0x11;
+    pub const TX_CONTROL: u8 = 0x14;
+    pub const CRC_RESULT_HIGH: u8 = 0x21;
+    pub const CRC_RESULT_LOW: u8 = 0x22;
+    pub const RF_CFG: u8 = 0x26;
+    pub const AUTO_TEST: u8 = 0x36;
+    pub const VERSION: u8 = 0x37;
+}
+
+mod command {
+    pub const IDLE: u8 = 0x00;
+    pub const MEM: u8 = 0x01;
+    pub const CALC_CRC: u8 = 0x03;
+    pub const TRANSCEIVE: u8 = 0x0C;
+    pub const MF_AUTHENT: u8 = 0x0E;
+    pub const SOFT_RESET: u8 = 0x0F;
+}
+
+const TIMER_IRQ: u8 = 0x01;
+const IDLE_IRQ: u8 = 0x10;
+const RX_IRQ: u8 = 0x20;
+const CRC_IRQ: u8 = 0x04;
+const MF_CRYPTO1_ON: u8 = 0x08;
+/// Soft power down, the analog part and the oscillator are off
+const POWER_DOWN: u8 = 0x10;
+/// The IRQ pin is low while an interrupt is pending
+const IRQ_INV: u8 = 0x80;
+/// Enabled interrupts of the IRQ pin in ComIEn and DivIEn
+const COM_IRQ_MASK: u8 = 0x7F;
+const DIV_IRQ_MASK: u8 = 0x14;
+const START_SEND: u8 = 0x80;
+const ANTENNA_ON: u8 = 0x03;
+/// The bits of the interrupt flags are set instead of cleared
+const SET_BITS: u8 = 0x80;
+const FLUSH_FIFO: u8 = 0x80;
+/// The next CRC calculation runs the digital self-test
+const SELF_TEST_ENABLE: u8 = 0x09;
+
+/// Results of the self-test of the MFRC522 1.0 (0x91), 2.0 (0x92) and the FM17522 clone (0x88)
+const SELF_TEST_RESULTS: [(u8, [u8; 64]); 3] = [
+    (
+        0x91,
+        [
+            0x00, 0xC6, 0x37, 0xD5, 0x32, 0xB7, 0x57, 0x5C, 0xC2, 0xD8, 0x7C, 0x4D, 0xD9, 0x70,
+            0xC7, 0x73, 0x10, 0xE6, 0xD2, 0xAA, 0x5E, 0xA1, 0x3E, 0x5A, 0x14, 0xAF, 0x30, 0x61,
+            0xC9, 0x70, 0xDB, 0x2E, 0x64, 0x22, 0x72, 0xB5, 0xBD, 0x65, 0xF4, 0xEC, 0x22, 0xBC,
+            0xD3, 0x72, 0x35, 0xCD, 0xAA, 0x41, 0x1F, 0xA7, 0xF3, 0x53, 0x14, 0xDE, 0x7E, 0x02,
+            0xD9, 0x0F, 0xB5, 0x5E, 0x25, 0x1D, 0x29, 0x79,
+        ],
+    ),
+    (
+        0x92,
+        [
+            0x00, 0xEB, 0x66, 0xBA, 0x57, 0xBF, 0x23, 0x95, 0xD0, 0xE3, 0x0D, 0x3D, 0x27, 0x89,
+            0x5C, 0xDE, 0x9D, 0x3B, 0xA7, 0x00, 0x21, 0x5B, 0x89, 0x82, 0x51, 0x3A, 0xEB, 0x02,
+            0x0C, 0xA5, 0x00, 0x49, 0x7C, 0x84, 0x4D, 0xB3, 0xCC, 0xD2, 0x1B, 0x81, 0x5D, 0x48,
+            0x76, 0xD5, 0x71, 0x61, 0x21, 0xA9, 0x86, 0x96, 0x83, 0x38, 0xCF, 0x9D, 0x5B, 0x6D,
+            0xDC, 0x15, 0xBA, 0x3E, 0x7D, 0x95, 0x3B, 0x2F,
+        ],
+    ),
+    (
+        0x88,
+        [
+            0x00, 0xD6, 0x78, 0x8C, 0xE2, 0xAA, 0x0C, 0x18, 0x2A, 0xB8, 0x7A, 0x7F, 0xD3, 0x6A,
+            0xCF, 0x0B, 0xB1, 0x37, 0x63, 0x4B, 0x69, 0xAE, 0x91, 0xC7, 0xC3, 0x97, 0xAE, 0x77,
+            0xF4, 0x37, 0xD7, 0x9B, 0x7C, 0xF5, 0x3C, 0x11, 0x8F, 0x15, 0xC3, 0xD7, 0xC1, 0x5B,
+            0x00, 0x2A, 0xD0, 0x75, 0xDE, 0x9E, 0x51, 0x64, 0xAB, 0x3E, 0xE9, 0x15, 0xB5, 0xAB,
+            0x56, 0x9A, 0x98, 0x82, 0x26, 0xEA, 0x2A, 0x62,
+        ],
+    ),
+];
+
+const PICC_REQA: u8 = 0x26;
+const PICC_WUPA: u8 = 0x52;
+const PICC_CASCADE_TAG: u8 = 0x88;
+const PICC_HLTA: u8 = 0x50;
+const PICC_READ: u8 = 0x30;
+const PICC_WRITE: u8 = 0xA0;
+const PICC_NTAG_WRITE: u8 = 0xA2;
+const PICC_AUTH_A: u8 = 0x60;
+const PICC_AUTH_B: u8 = 0x61;
+const PICC_ACK: u8 = 0x0A;
+/// Select commands of the cascade levels 1 to 3
+const PICC_SELECT: [u8; 3] = [0x93, 0x95, 0x97];
+
+/// Failure of the SPI that is scripted by the test
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SpiError;
+
+/// ISO14443A state of the tag
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum TagState {
+    Idle,
+    /// Answered the wakeup, the UID is selected level by level
+    Ready(usize),
+    Active,
+    Halt,
+}
+
+/// Virtual MFRC522 with an optional tag in the field
+#[derive(Debug)]
+pub struct Mfrc522Emulator {
+    version: u8,
+    registers: [u8; 64],
+    fifo: VecDeque<u8>,
+    tag: Option<Tag>,
+    state: TagState,
+    /// Sector and key of the running crypto session
+    session: Option<(u8, KeyType)>,
+    /// Block of a write that waits for its data
+    pending_write: Option<u8>,
+    /// Frames the tag does not answer
+    lost_answers: u32,
+    /// Read commands the tag does not answer
+    failing_reads: u32,
+    /// SPI transfers that fail
+    failing_transfers: u32,
+    /// The self-test returns a wrong result
+    broken: bool,
+    /// Frames that have been sent to the tag
+    frames: Vec<Vec<u8>>,
+    resets: u32,
+}
+
+impl Default for Mfrc522Emulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mfrc522Emulator {
+    /// Power up the emulator without a tag in the field
+    pub fn new() -> Self {
+        let mut emulator = Self {
+            version: DEFAULT_VERSION,
+            registers: [0; 64],
+            fifo: VecDeque::new(),
+            tag: None,
+            state: TagState::Idle,
+            session: None,
+            pending_write: None,
+            lost_answers: 0,
+            failing_reads: 0,
+            failing_transfers: 0,
+            broken: false,
+            frames: Vec::new(),
+            resets: 0,
+        };
+        emulator.reset_registers();
+        emulator
+    }
+
+    /// Version that is reported, e.g. 0x91, 0x88 for clones or 0x00 for a missing chip
+    pub fn set_version(&mut self, version: u8) {
+        self.version = version;
+        self.registers[usize::from(register::VERSION)] = version;
+    }
+
+    /// Put a tag into the field. It has to be woken up before it answers.
+    pub fn place_tag(&mut self, tag: Tag) {
+        self.tag = Some(tag);
+        self.state = TagState::Idle;
+        self.session = None;
+        self.pending_write = None;
+    }
+
+    /// Take the tag out of the field
+    pub fn remove_tag(&mut self) -> Option<Tag> {
+        self.state = TagState::Idle;
+        self.session = None;
+        self.pending_write = None;
+        self.tag.take()
+    }
+
+    pub fn tag(&self) -> Option<&Tag> {
+        self.tag.as_ref()
+    }
+
+    /// The next frames are not answered, like a tag at the edge of the field
+    pub fn lose_answers(&mut self, frames: u32) {
+        self.lost_answers = frames;
+    }
+
+    /// The next read commands are not answered
+    pub fn fail_reads(&mut self, reads: u32) {
+        self.failing_reads = reads;
+    }
+
+    /// The next SPI transfers fail
+    pub fn fail_transfers(&mut self, transfers: u32) {
+        self.failing_transfers = transfers;
+    }
+
+    /// The self-test returns a wrong result like on a defect reader board
+    pub fn break_self_test(&mut self) {
+        self.broken = true;
+    }
+
+    /// Gain of the receiver, bits 6 to 4 of the RF configuration
+    pub fn antenna_gain(&self) -> u8 {
+        (self.registers[usize::from(register::RF_CFG)] >> 4) & 0x07
+    }
+
+    /// Lose the configuration like after a voltage drop, the antenna is off afterwards
+    pub fn brownout(&mut self) {
+        self.reset_registers();
+    }
+
+    /// Frames that have been sent to the tag without the CRC
+    pub fn frames(&self) -> &[Vec<u8>] {
+        &self.frames
+    }
+
+    /// Number of soft resets
+    pub fn resets(&self) -> u32 {
+        self.resets
+    }
+
+    /// The MFRC522 is in soft power down
+    pub fn is_powered_down(&self) -> bool {
+        self.registers[usize::from(register::COMMAND)] & POWER_DOWN != 0
+    }
+
+    /// Level of the IRQ pin. An enabled interrupt drives it high, or low with IRqInv.
+    pub fn irq_low(&self) -> bool {
+        let com_ien = self.registers[usize::from(register::COM_IEN)];
+        let div_ien = self.registers[usize::from(register::DIV_IEN)];
+        let pending = com_ien & self.registers[usize::from(register::COM_IRQ)] & COM_IRQ_MASK != 0
+            || div_ien & self.registers[usize::from(register::DIV_IRQ)] & DIV_IRQ_MASK != 0;
+        pending == (com_ien & IRQ_INV != 0)
+    }
+
+    pub fn antenna_on(&self) -> bool {
+        self.registers[usize::from(register::TX_CONTROL)] & ANTENNA_ON == ANTENNA_ON
+    }
+
+    /// Value of a register without side effects
+    pub fn register(&self, address: u8) -> u8 {
+        match address {
+            register::FIFO_LEVEL => self.fifo.len() as u8,
+            _ => self.registers[usize::from(address & 0x3F)],
+        }
+    }
+
+    fn reset_registers(&mut self) {
+        self.registers = [0; 64];
+        self.registers[usize::from(register::COMMAND)] = 0x20;
+        self.registers[usize::from(register::COM_IEN)] = 0x80;
+        self.registers[usize::from(register::MODE)] = 0x3F;
+        self.registers[usize::from(register::TX_CONTROL)] = 0x80;
+        self.registers[usize::from(register::RF_CFG)] = 0x48;
+        self.registers[usize::from(register::VERSION)] = self.version;
+        self.fifo.clear();
+    }
+
+    fn read_register(&mut self, address: u8) -> u8 {
+        match address {
+            register::FIFO_DATA => self.fifo.pop_front().unwrap_or(0),
+            _ => self.register(address),
+        }
+    }
+
+    fn write_register(&mut self, address: u8, value: u8) {
+        let index = usize::from(address);
+        match address {
+            register::COMMAND => {
+                self.registers[index] = value;
+                self.execute(value & 0x0F);
+            }
+            register::FIFO_DATA => self.fifo.push_back(value),
+            register::FIFO_LEVEL => {
+                if value & FLUSH_FIFO != 0 {
+                    self.fifo.clear();
+                }
+            }
+            register::COM_IRQ | register::DIV_IRQ => {
+                if value & SET_BITS != 0 {
+                    self.registers[index] |= value & !SET_BITS;
+                } else {
+                    self.registers[index] &= !value;
+                }
+            }
+            register::BIT_FRAMING => {
+                self.registers[index] = value;
+                if value & START_SEND != 0 && self.command() == command::TRANSCEIVE {
+                    self.transceive();
+                }
+            }
+            register::VERSION => (),
+            _ => self.registers[index] = value,
+        }
+    }
+
+    fn command(&self) -> u8 {
+        self.registers[usize::from(register::COMMAND)] & 0x0F
+    }
+
+    fn set_irq(&mut self, irq: u8) {
+        self.registers[usize::from(register::COM_IRQ)] |= irq;
+    }
+
+    fn execute(&mut self, command: u8) {
+        match command {
+            // The FIFO is moved to the internal buffer
+            command::MEM => self.fifo.clear(),
+            command::CALC_CRC
+                if self.registers[usize::from(register::AUTO_TEST)] & 0x0F == SELF_TEST_ENABLE =>
+            {
+                self.fifo.clear();
+                let result = SELF_TEST_RESULTS
+                    .iter()
+                    .find(|(version, _)| *version == self.version)
+                    .map_or([0; 64], |(_, result)| *result);
+                self.fifo.extend(result.iter());
+                if self.broken {
+                    self.fifo[1] ^= 0xFF;
+                }
+                self.registers[usize::from(register::DIV_IRQ)] |= CRC_IRQ;
+            }
+            command::CALC_CRC => {
+                let data: Vec<u8> = self.fifo.drain(..).collect();
+                let crc = crc_a(&data);
+                self.registers[usize::from(register::CRC_RESULT_LOW)] = crc[0];
+                self.registers[usize::from(register::CRC_RESULT_HIGH)] = crc[1];
+                self.registers[usize::from(register::DIV_IRQ)] |= CRC_IRQ;
+            }
+            command::TRANSCEIVE
+                if self.registers[usize::from(register::BIT_FRAMING)] & START_SEND != 0 =>
+            {
+                self.transceive()
+            }
+            command::MF_AUTHENT => self.authenticate(),
+            command::SOFT_RESET => {
+                self.resets += 1;
+                self.reset_registers();
+                self.registers[usize::from(register::COMMAND)] = command::IDLE;
+            }
+            _ => (),
+        }
+    }
+
+    fn authenticate(&mut self) {
+        let data: Vec<u8> = self.fifo.drain(..).collect();
+        let key_type = match data.first() {
+            Some(&PICC_AUTH_A) => KeyType::A,
+            Some(&PICC_AUTH_B) => KeyType::B,
+            _ => return self.set_irq(TIMER_IRQ),
+        };
+        let accepted = data.len() >= 12
+            && self.state == TagState::Active
+            && self.answers()
+            && matches!(&self.tag, Some(tag) if tag.key_matches(data[1], key_type, &data[2..8]));
+        if accepted {
+            self.session = Some((sector_of(data[1]), key_type));
+            self.registers[usize::from(register::STATUS2)] |= MF_CRYPTO1_ON;
+            self.set_irq(IDLE_IRQ);
+        } else {
+            // The tag stops answering after a failed authentication
+            self.state = TagState::Idle;
+            self.session = None;
+            self.registers[usize::from(register::STATUS2)] &= !MF_CRYPTO1_ON;
+            self.set_irq(TIMER_IRQ);
+        }
+    }
+
+    fn transceive(&mut self) {
+        let frame: Vec<u8> = self.fifo.drain(..).collect();
+        let bits = self.registers[usize::from(register::BIT_FRAMING)] & 0x07;
+        self.registers[usize::from(register::BIT_FRAMING)] &= !START_SEND;
+        self.registers[usize::from(register::ERROR)] = 0;
+
+        let answer = if self.answers() {
+            self.answer(&frame, bits)
+        } else {
+            None
+        };
+        match answer {
+            Some((data, last_bits)) => {
+                self.fifo.extend(data);
+                self.registers[usize::from(register::CONTROL)] = last_bits;
+                self.set_irq(RX_IRQ | IDLE_IRQ);
+            }
+            None => self.set_irq(TIMER_IRQ),
+        }
+        self.frames.push(frame);
+    }
+
+    /// A tag is in the field of the antenna and its answer is not lost
+    fn answers(&mut self) -> bool {
+        if self.tag.is_none() || !self.antenna_on() || self.is_powered_down() {
+            return false;
+        }
+        // Frames are encrypted until the crypto is stopped, only the tag of the session
+        // understands them
+        if self.registers[usize::from(register::STATUS2)] & MF_CRYPTO1_ON != 0
+            && self.session.is_none()
+        {
+            return false;
+        }
+        if self.lost_answers > 0 {
+            self.lost_answers -= 1;
+            return false;
+        }
+        true
+    }
+
+    /// Answer of the tag with the number of valid bits in the last byte (0 for all)
+    fn answer(&mut self, frame: &[u8], bits: u8) -> Option<(Vec<u8>, u8)> {
+        let tag = self.tag.as_mut()?;
+        let uid = tag.uid().to_vec();
+        let levels = cascade_levels(&uid);
+
+        // Short frames wake the tag up
+        if bits == 7 && frame.len() == 1 {
+            let wakes = match frame[0] {
+                PICC_WUPA => true,
+                PICC_REQA => self.state != TagState::Halt,
+                _ => false,
+            };
+            if !wakes {
+                return None;
+            }
+            self.state = TagState::Ready(0);
+            self.session = None;
+            self.pending_write = None;
+            let atqa = if uid.len() == 4 { 0x04 } else { 0x44 };
+            return Some((vec![atqa, 0x00], 0));
+        }
+
+        match self.state {
+            TagState::Ready(level) => {
+                let cascade = &levels[level];
+                let bcc = cascade.iter().fold(0, |bcc, byte| bcc ^ byte);
+                match frame {
+                    [select, 0x20] if *select == PICC_SELECT[level] => {
+                        let mut answer = cascade.to_vec();
+                        answer.push(bcc);
+                        Some((answer, 0))
+                    }
+                    [select, 0x70, rest @ ..]
+                        if *select == PICC_SELECT[level]
+                            && rest.len() == 7
+                            && rest[..4] == cascade[..]
+                            && rest[4] == bcc
+                            && check_crc(frame) =>
+                    {
+                        let sak = if level + 1 < levels.len() {
+                            self.state = TagState::Ready(level + 1);
+                            0x04
+                        } else {
+                            self.state = TagState::Active;
+                            match tag.kind() {
+                                TagKind::MifareClassic => 0x08,
+                                TagKind::Ntag => 0x00,
+                            }
+                        };
+                        Some(with_crc(vec![sak]))
+                    }
+                    _ => {
+                        self.state = TagState::Idle;
+                        None
+                    }
+                }
+            }
+            TagState::Active => {
+                if !check_crc(frame) {
+                    return None;
+                }
+                let payload = &frame[..frame.len() - 2];
+                if let Some(block) = self.pending_write.take() {
+                    tag.store(block, &payload[..payload.len().min(unit_size(tag))]);
+                    return Some((vec![PICC_ACK], 4));
+                }
+                match payload {
+                    [PICC_HLTA, 0x00] => {
+                        self.state = TagState::Halt;
+                        self.session = None;
+                        None
+                    }
+                    [PICC_READ, address] => {
+                        if self.failing_reads > 0 {
+                            self.failing_reads -= 1;
+                            return None;
+                        }
+                        let allowed = match tag.kind() {
+                            TagKind::MifareClassic => {
+                                self.session.map(|(sector, _)| sector) == Some(sector_of(*address))
+                            }
+                            TagKind::Ntag => *address < tag.size(),
+                        };
+                        if allowed {
+                            Some(with_crc(tag.read(*address).to_vec()))
+                        } else {
+                            self.state = TagState::Idle;
+                            None
+                        }
+                    }
+                    [PICC_WRITE, address] => {
+                        let allowed = match (tag.kind(), self.session) {
+                            (TagKind::MifareClassic, Some((sector, key_type))) => {
+                                sector == sector_of(*address) && tag.may_write(*address, key_type)
+                            }
+                            (TagKind::MifareClassic, None) => false,
+                            (TagKind::Ntag, _) => tag.may_write(*address, KeyType::A),
+                        };
+                        if allowed {
+                            self.pending_write = Some(*address);
+                            Some((vec![PICC_ACK], 4))
+                        } else {
+                            // NAK
+                            Some((vec![0x00], 4))
+                        }
+                    }
+                    [PICC_NTAG_WRITE, page, data @ ..]
+                        if tag.kind() == TagKind::Ntag && data.len() == 4 =>
+                    {
+                        if tag.may_write(*page, KeyType::A) {
+                            tag.store(*page, data);
+                            Some((vec![PICC_ACK], 4))
+                        } else {
+                            Some((vec![0x00], 4))
+                        }
+                    }
+                    _ => None,
+                }
+            }
+            TagState::Idle | TagState::Halt => None,
+        }
+    }
+}
+
+/// Bytes of the UID per cascade level, the cascade tag marks that more bytes follow
+fn cascade_levels(uid: &[u8]) -> Vec<[u8; 4]> {
+    let mut levels = Vec::new();
+    let mut rest = uid;
+    while rest.len() > 4 {
+        levels.push([PICC_CASCADE_TAG, rest[0], rest[1], rest[2]]);
+        rest = &rest[3..];
+    }
+    let mut last = [0; 4];
+    last[..rest.len()].copy_from_slice(rest);
+    levels.push(last);
+    levels
+}
+
+/// The trailer of MIFARE Classic cards is written with 16 bytes, pages of NTAGs with 4
+fn unit_size(tag: &Tag) -> usize {
+    match tag.kind() {
+        TagKind::MifareClassic => 16,
+        TagKind::Ntag => 4,
+    }
+}
+
+/// CRC_A of ISO14443A, the low byte is sent first
+pub fn crc_a(data: &[u8]) -> [u8; 2] {
+    let crc = data.iter().fold(0x6363_u16, |crc, byte| {
+        let mut byte = *byte ^ (crc as u8);
+        byte ^= byte << 4;
+        (crc >> 8) ^ (u16::from(byte) << 8) ^ (u16::from(byte) << 3) ^ (u16::from(byte) >> 4)
+    });
+    crc.to_le_bytes()
+}
+
+fn check_crc(frame: &[u8]) -> bool {
+    frame.len() > 2 && crc_a(&frame[..frame.len() - 2])[..] == frame[frame.len() - 2..]
+}
+
+fn with_crc(mut data: Vec<u8>) -> (Vec<u8>, u8) {
+    let crc = crc_a(&data);
+    data.extend_from_slice(&crc);
+    (data, 0)
+}
+
+impl Transfer<u8> for Mfrc522Emulator {
+    type Error = SpiError;
+
+    /// Every byte is the address of a read, the value is returned with the next byte
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], SpiError> {
+        if self.failing_transfers > 0 {
+            self.failing_transfers -= 1;
+            return Err(SpiError);
+        }
+        if let Some(address) = words.first().filter(|address| *address & 0x80 == 0) {
+            // A write with the answer of the chip
+            let address = (address >> 1) & 0x3F;
+            for value in words[1..].iter().copied() {
+                self.write_register(address, value);
+            }
+            words.iter_mut().for_each(|word| *word = 0);
+            return Ok(words);
+        }
+        let mut value = 0;
+        for word in words.iter_mut() {
+            let address = *word;
+            *word = value;
+            value = if address & 0x80 != 0 {
+                self.read_register((address >> 1) & 0x3F)
+            } else {
+                0
+            };
+        }
+        Ok(words)
+    }
+}
+
+impl Write<u8> for Mfrc522Emulator {
+    type Error = SpiError;
+
+    /// The first byte is the address, all others are written to the register
+    fn write(&mut self, words: &[u8]) -> Result<(), SpiError> {
+        if self.failing_transfers > 0 {
+            self.failing_transfers -= 1;
+            return Err(SpiError);
+        }
+        if let Some((address, values)) = words.split_first() {
+            let address = (address >> 1) & 0x3F;
+            for value in values {
+                self.write_register(address, *value);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Chip select pin of the emulator, the selection is not checked
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct ChipSelect;
+
+impl OutputPin for ChipSelect {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::BTreeMap;
+
+/// A file on the SD card
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Track {
+    /// Track in one of the folders `01` to `99`
+    Folder(u8, u8),
+    /// Track in the `mp3` folder
+    Mp3(u16),
+    /// Track in the `ADVERT` folder
+    Advert(u16),
+}
+
+/// Content of a virtual SD card. Only the number of tracks per folder is modelled.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct SdCard {
+    folders: BTreeMap<u8, u8>,
+    mp3: u16,
+    advert: u16,
+}
+
+impl SdCard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a folder `01` to `99` with the tracks `001.mp3` to `<tracks>.mp3`
+    pub fn with_folder(mut self, folder: u8, tracks: u8) -> Self {
+        self.folders.insert(folder, tracks);
+        self
+    }
+
+    /// Put the tracks `0001.mp3` to `<tracks>.mp3` into the `mp3` folder
+    pub fn with_mp3(mut self, tracks: u16) -> Self {
+        self.mp3 = tracks;
+        self
+    }
+
+    /// Put the tracks `0001.mp3` to `<tracks>.mp3` into the `ADVERT` folder
+    pub fn with_advert(mut self, tracks: u16) -> Self {
+        self.advert = tracks;
+        self
+    }
+
+    /// Number of tracks in a folder
+    pub fn folder_track_count(&self, folder: u8) -> Option<u8> {
+        self.folders.get(&folder).copied()
+    }
+
+    /// Number of folders `01` to `99`
+    pub fn folder_count(&self) -> u16 {
+        self.folders.len() as u16
+    }
+
+    /// Number of all files that can be played with next and previous
+    pub fn file_count(&self) -> u16 {
+        self.folders
+            .values()
+            .map(|tracks| u16::from(*tracks))
+            .sum::<u16>()
+            + self.mp3
+    }
+
+    pub fn contains(&self, track: Track) -> bool {
+        match track {
+            Track::Folder(folder, track) => match self.folder_track_count(folder) {
+                Some(tracks) => track >= 1 && track <= tracks,
+                None => false,
+            },
+            Track::Mp3(track) => track >= 1 && track <= self.mp3,
+            Track::Advert(track) => track >= 1 && track <= self.advert,
+        }
+    }
+
+    /// Number of the file in the order of the file system. The DFPlayer uses it in the track
+    /// finished notification and the current track answer. The folders come first, followed
+    /// by the `mp3` folder. Adverts have no number.
+    pub fn file_number(&self, track: Track) -> Option<u16> {
+        if !self.contains(track) {
+            return None;
+        }
+        match track {
+            Track::Folder(folder, track) => Some(
+                self.folders
+                    .range(..folder)
+                    .map(|(_, tracks)| u16::from(*tracks))
+                    .sum::<u16>()
+                    + u16::from(track),
+            ),
+            Track::Mp3(track) => Some(self.file_count() - self.mp3 + track),
+            Track::Advert(_) => None,
+        }
+    }
+
+    /// Track with the given file number
+    pub fn track(&self, number: u16) -> Option<Track> {
+        if number == 0 {
+            return None;
+        }
+        let mut first = 1;
+        for (folder, tracks) in self.folders.iter() {
+            let tracks = u16::from(*tracks);
+            if number < first + tracks {
+                return Some(Track::Folder(*folder, (number - first + 1) as u8));
+            }
+            first += tracks;
+        }
+        if number < first + self.mp3 {
+            Some(Track::Mp3(number - first + 1))
+        } else {
+            None
+        }
+    }
+}
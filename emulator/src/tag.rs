@@ -0,0 +1,179 @@
+/// Family of a virtual tag
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TagKind {
+    /// MIFARE Classic 1K with 64 blocks of 16 bytes and a 4 byte UID
+    MifareClassic,
+    /// NTAG213 with 45 pages of 4 bytes and a 7 byte UID
+    Ntag,
+}
+
+/// Key of a MIFARE Classic sector
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum KeyType {
+    A,
+    B,
+}
+
+const CLASSIC_BLOCKS: usize = 64;
+const CLASSIC_BLOCK_SIZE: usize = 16;
+const NTAG_PAGES: usize = 45;
+const NTAG_PAGE_SIZE: usize = 4;
+/// Key A, access bits, general purpose byte and key B of new MIFARE Classic cards
+const TRANSPORT_TRAILER: [u8; 16] = [
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x07, 0x80, 0x69, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+
+/// Virtual ISO14443A tag with its memory
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Tag {
+    kind: TagKind,
+    uid: Vec<u8>,
+    memory: Vec<u8>,
+}
+
+impl Tag {
+    /// New MIFARE Classic 1K card with transport keys and access bits
+    pub fn mifare_classic(uid: [u8; 4]) -> Self {
+        let mut memory = vec![0; CLASSIC_BLOCKS * CLASSIC_BLOCK_SIZE];
+        for trailer in memory.chunks_mut(4 * CLASSIC_BLOCK_SIZE) {
+            trailer[3 * CLASSIC_BLOCK_SIZE..].copy_from_slice(&TRANSPORT_TRAILER);
+        }
+        memory[..4].copy_from_slice(&uid);
+        memory[4] = uid.iter().fold(0, |bcc, byte| bcc ^ byte);
+        Self {
+            kind: TagKind::MifareClassic,
+            uid: uid.to_vec(),
+            memory,
+        }
+    }
+
+    /// New NTAG213 with empty user pages
+    pub fn ntag(uid: [u8; 7]) -> Self {
+        let mut memory = vec![0; NTAG_PAGES * NTAG_PAGE_SIZE];
+        memory[..3].copy_from_slice(&uid[..3]);
+        memory[4..8].copy_from_slice(&uid[3..]);
+        Self {
+            kind: TagKind::Ntag,
+            uid: uid.to_vec(),
+            memory,
+        }
+    }
+
+    /// Store 16 bytes at the block of a MIFARE Classic card or the four pages from the page
+    /// of a NTAG
+    pub fn with_data(mut self, address: u8, data: [u8; 16]) -> Self {
+        self.store(address, &data);
+        self
+    }
+
+    pub fn kind(&self) -> TagKind {
+        self.kind
+    }
+
+    pub fn uid(&self) -> &[u8] {
+        &self.uid
+    }
+
+    /// 16 bytes from the block or four pages from the page
+    pub fn data(&self, address: u8) -> [u8; 16] {
+        let mut data = [0; 16];
+        for (index, byte) in data.iter_mut().enumerate() {
+            let offset = usize::from(address) * self.unit_size() + index;
+            *byte = self.memory[offset % self.memory.len()];
+        }
+        data
+    }
+
+    /// Number of blocks or pages
+    pub(crate) fn size(&self) -> u8 {
+        (self.memory.len() / self.unit_size()) as u8
+    }
+
+    fn unit_size(&self) -> usize {
+        match self.kind {
+            TagKind::MifareClassic => CLASSIC_BLOCK_SIZE,
+            TagKind::Ntag => NTAG_PAGE_SIZE,
+        }
+    }
+
+    /// Write the bytes from the block or page on, bytes beyond the memory are ignored
+    pub(crate) fn store(&mut self, address: u8, data: &[u8]) {
+        let offset = (usize::from(address) * self.unit_size()).min(self.memory.len());
+        let len = data.len().min(self.memory.len() - offset);
+        self.memory[offset..offset + len].copy_from_slice(&data[..len]);
+    }
+
+    /// The 16 bytes of a read command. Keys can not be read from the trailer.
+    pub(crate) fn read(&self, address: u8) -> [u8; 16] {
+        let mut data = self.data(address);
+        if self.kind == TagKind::MifareClassic && is_trailer(address) {
+            data[..6].copy_from_slice(&[0; 6]);
+        }
+        data
+    }
+
+    /// Check the key of the sector with the block
+    pub(crate) fn key_matches(&self, block: u8, key_type: KeyType, key: &[u8]) -> bool {
+        let trailer = self.data(trailer_of(block));
+        let stored = match key_type {
+            KeyType::A => &trailer[..6],
+            KeyType::B => &trailer[10..],
+        };
+        self.kind == TagKind::MifareClassic && stored == key
+    }
+
+    /// Check the access conditions of the sector trailer for writing the block
+    pub(crate) fn may_write(&self, block: u8, key_type: KeyType) -> bool {
+        if self.kind == TagKind::Ntag {
+            // The UID and lock pages are not changed
+            return block >= 4 && block < self.size();
+        }
+        if block == 0 || block >= self.size() {
+            return false;
+        }
+        let trailer = self.data(trailer_of(block));
+        let condition = access_condition(&trailer[6..9], block % 4);
+        let condition = match condition {
+            Some(condition) => condition,
+            None => return false,
+        };
+        if is_trailer(block) {
+            match condition {
+                0b001 => true,
+                0b011 | 0b100 => key_type == KeyType::B,
+                _ => false,
+            }
+        } else {
+            match condition {
+                0b000 => true,
+                0b011 | 0b100 | 0b110 => key_type == KeyType::B,
+                _ => false,
+            }
+        }
+    }
+}
+
+fn is_trailer(block: u8) -> bool {
+    block % 4 == 3
+}
+
+pub(crate) fn sector_of(block: u8) -> u8 {
+    block / 4
+}
+
+fn trailer_of(block: u8) -> u8 {
+    block | 0x03
+}
+
+/// C1 C2 C3 of the block in the sector, None if the inverted bits don't match
+fn access_condition(bytes: &[u8], index: u8) -> Option<u8> {
+    let c1 = bytes[1] >> 4;
+    let c2 = bytes[2] & 0x0F;
+    let c3 = bytes[2] >> 4;
+    if bytes[0] & 0x0F != !c1 & 0x0F || bytes[0] >> 4 != !c2 & 0x0F || bytes[1] & 0x0F != !c3 & 0x0F
+    {
+        return None;
+    }
+    let bit = |nibble: u8| (nibble >> index) & 1;
+    Some((bit(c1) << 2) | (bit(c2) << 1) | bit(c3))
+}
@@ -0,0 +1,234 @@
+//! The DFPlayer driver of the firmware talking to the emulator
+
+use dfplayer_emulator::{Emulator, PlayState, SdCard, Track, ERROR_NOT_FOUND, MEDIA_SD};
+use embedded_hal::serial::{Read, Write};
+use music_box_drivers::player::PlayState as Status;
+use music_box_drivers::player::{
+    ChipVariant, Clock, Command, DFPlayer, Equalizer, Error, PlaybackDevice, Response,
+};
+use std::cell::{Cell, RefCell};
+use std::convert::Infallible;
+use std::rc::Rc;
+
+/// Serial port of the driver, the test keeps access to the emulator
+struct Port(Rc<RefCell<Emulator>>);
+
+impl Read<u8> for Port {
+    type Error = Infallible;
+
+    fn read(&mut self) -> nb::Result<u8, Infallible> {
+        self.0.borrow_mut().read()
+    }
+}
+
+impl Write<u8> for Port {
+    type Error = Infallible;
+
+    fn write(&mut self, byte: u8) -> nb::Result<(), Infallible> {
+        self.0.borrow_mut().write(byte)
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Infallible> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+/// Every look at the clock lets one millisecond pass in the emulator
+struct EmulatorClock {
+    emulator: Rc<RefCell<Emulator>>,
+    now: Cell<u32>,
+}
+
+impl Clock for EmulatorClock {
+    type Instant = u32;
+    type Duration = u32;
+
+    fn now(&self) -> u32 {
+        self.emulator.borrow_mut().advance(1);
+        self.now.set(self.now.get() + 1);
+        self.now.get()
+    }
+
+    fn millis(&self, ms: u32) -> u32 {
+        ms
+    }
+}
+
+type Player = DFPlayer<Port, EmulatorClock>;
+
+/// Driver connected to a booted emulator
+fn setup(emulator: Emulator) -> (Player, Rc<RefCell<Emulator>>) {
+    let emulator = Rc::new(RefCell::new(emulator));
+    emulator.borrow_mut().set_boot_ms(1000);
+    // Power up, the online notification is missed before the driver starts
+    emulator.borrow_mut().advance(1500);
+    while emulator.borrow_mut().read().is_ok() {}
+    let clock = EmulatorClock {
+        emulator: emulator.clone(),
+        now: Cell::new(0),
+    };
+    let mut player = DFPlayer::new(Port(emulator.clone()), clock);
+    player.set_chip_variant(ChipVariant::Yx5200);
+    (player, emulator)
+}
+
+fn card() -> SdCard {
+    SdCard::new()
+        .with_folder(1, 3)
+        .with_folder(2, 12)
+        .with_mp3(5)
+}
+
+#[test]
+fn startup_reports_the_sd_card() {
+    let (mut player, emulator) = setup(Emulator::new(card()));
+    assert_eq!(player.startup(), Ok(MEDIA_SD));
+    assert!(player.has_sd_card());
+    assert!(player.config_pending());
+    assert!(emulator.borrow().is_online());
+    assert_eq!(emulator.borrow().received(), &[(0x0C, 0)]);
+}
+
+#[test]
+fn startup_without_sd_card() {
+    let (mut player, _) = setup(Emulator::without_card());
+    assert_eq!(player.startup(), Ok(0));
+    assert!(!player.has_sd_card());
+}
+
+#[test]
+fn startup_without_online_notification() {
+    let (mut player, _) = setup(Emulator::new(card()));
+    // The GD3200B is asked for the files instead
+    player.set_chip_variant(ChipVariant::Gd3200b);
+    assert_eq!(player.startup(), Ok(MEDIA_SD));
+    assert!(player.has_sd_card());
+}
+
+#[test]
+fn queries() {
+    let (mut player, _) = setup(Emulator::new(card()));
+    player.startup().unwrap();
+
+    assert_eq!(player.file_count(), Ok(20));
+    assert_eq!(player.folder_track_count(2), Ok(12));
+    assert_eq!(player.status(), Ok(Status::Stopped));
+
+    player.set_volume(20).unwrap();
+    assert_eq!(player.volume(), Ok(20));
+
+    player.play(2, 5).unwrap();
+    assert_eq!(player.status(), Ok(Status::Playing));
+    assert_eq!(player.current_track(), Ok(3 + 5));
+}
+
+#[test]
+fn query_of_missing_folder_fails() {
+    let (mut player, _) = setup(Emulator::new(card()));
+    player.startup().unwrap();
+    assert_eq!(
+        player.folder_track_count(7),
+        Err(Error::Module(ERROR_NOT_FOUND as u8))
+    );
+}
+
+#[test]
+fn commands_are_acknowledged() {
+    let (mut player, emulator) = setup(Emulator::new(card()));
+    player.startup().unwrap();
+    emulator.borrow_mut().take_received();
+
+    player.set_ack_retries(Some(3));
+    assert_eq!(player.play(1, 2), Ok(()));
+    assert_eq!(emulator.borrow().received(), &[(0x0F, 0x0102)]);
+    assert_eq!(emulator.borrow().track(), Some(Track::Folder(1, 2)));
+    // The acknowledgement is not passed to the application
+    assert_eq!(player.poll(), None);
+}
+
+#[test]
+fn lost_commands_are_repeated() {
+    let (mut player, emulator) = setup(Emulator::new(card()));
+    player.startup().unwrap();
+    emulator.borrow_mut().take_received();
+
+    player.set_ack_retries(Some(3));
+    emulator.borrow_mut().lose_frames(2);
+    assert_eq!(player.play(1, 3), Ok(()));
+    assert_eq!(emulator.borrow().received(), &[(0x0F, 0x0103)]);
+    assert_eq!(emulator.borrow().state(), PlayState::Playing);
+}
+
+#[test]
+fn lost_commands_are_reported() {
+    let (mut player, emulator) = setup(Emulator::new(card()));
+    player.startup().unwrap();
+
+    player.set_ack_retries(Some(3));
+    emulator.borrow_mut().lose_frames(4);
+    assert_eq!(player.pause(), Err(Error::NoAck));
+}
+
+#[test]
+fn finished_track_is_notified() {
+    let (mut player, emulator) = setup(Emulator::new(card()));
+    player.startup().unwrap();
+
+    emulator.borrow_mut().set_track_ms(2000);
+    player.play(2, 1).unwrap();
+    emulator.borrow_mut().advance(1000);
+    assert_eq!(player.poll(), None);
+    emulator.borrow_mut().advance(1000);
+    assert_eq!(player.poll(), Some(Response::TrackFinished(3 + 1)));
+    assert_eq!(player.poll(), None);
+}
+
+#[test]
+fn media_changes_are_notified() {
+    let (mut player, emulator) = setup(Emulator::new(card()));
+    player.startup().unwrap();
+    while player.config_pending() {
+        player.apply_config().unwrap();
+    }
+
+    emulator.borrow_mut().remove_card();
+    assert_eq!(player.poll(), Some(Response::MediaRemoved(MEDIA_SD)));
+    assert!(!player.has_sd_card());
+
+    emulator.borrow_mut().insert_card(card());
+    assert_eq!(player.poll(), Some(Response::MediaInserted(MEDIA_SD)));
+    assert!(player.has_sd_card());
+    // The DFPlayer forgot the configuration
+    assert!(player.config_pending());
+}
+
+#[test]
+fn configuration_is_restored_after_wake_up() {
+    let (mut player, emulator) = setup(Emulator::new(card()));
+    player.startup().unwrap();
+    player.configure(Equalizer::Bass, PlaybackDevice::SdCard);
+    while player.config_pending() {
+        player.apply_config().unwrap();
+    }
+    assert_eq!(emulator.borrow().equalizer(), Equalizer::Bass as u8);
+
+    player.send(Command::Standby).unwrap();
+    assert!(emulator.borrow().is_standby());
+    assert!(player.is_sleeping());
+
+    emulator.borrow_mut().take_received();
+    player.wake().unwrap();
+    assert!(!emulator.borrow().is_standby());
+    assert!(player.config_pending());
+    player.apply_config().unwrap();
+    player.apply_config().unwrap();
+    assert!(!player.config_pending());
+    assert_eq!(
+        emulator.borrow().received(),
+        &[
+            (0x0B, 0),
+            (0x09, PlaybackDevice::SdCard as u16),
+            (0x07, Equalizer::Bass as u16)
+        ]
+    );
+}
@@ -0,0 +1,491 @@
+//! The tag reader driver of the firmware talking to the emulated MFRC522
+
+use dfplayer_emulator::mfrc522::{ChipSelect, Mfrc522Emulator, SpiError};
+use dfplayer_emulator::Tag;
+use embedded_hal::blocking::spi::{Transfer, Write};
+use music_box_drivers::card::Card;
+use music_box_drivers::tagreader::{
+    CardKey, KeyType, SelfTest, TagChange, TagReader, TagReaderError, WriteError,
+    DEFAULT_CARD_BLOCK,
+};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// SPI of the driver, the test keeps access to the emulator
+struct Spi(Rc<RefCell<Mfrc522Emulator>>);
+
+impl Transfer<u8> for Spi {
+    type Error = SpiError;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], SpiError> {
+        self.0.borrow_mut().transfer(words)
+    }
+}
+
+impl Write<u8> for Spi {
+    type Error = SpiError;
+
+    fn write(&mut self, words: &[u8]) -> Result<(), SpiError> {
+        self.0.borrow_mut().write(words)
+    }
+}
+
+type Reader = TagReader<Spi, ChipSelect>;
+
+/// Driver connected to the emulator
+fn setup() -> (Reader, Rc<RefCell<Mfrc522Emulator>>) {
+    let emulator = Rc::new(RefCell::new(Mfrc522Emulator::new()));
+    let reader = TagReader::new(Spi(emulator.clone()), ChipSelect).unwrap();
+    (reader, emulator)
+}
+
+const CLASSIC_UID: [u8; 4] = [0xDE, 0xAD, 0xBE, 0xEF];
+const NTAG_UID: [u8; 7] = [0x04, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66];
+
+fn card() -> Card {
+    Card::new(7, 2, 0, 0)
+}
+
+fn new_tag(reader: &mut Reader) -> mfrc522::Uid {
+    match reader.check_for_tag() {
+        Some(TagChange::New(uid)) => uid,
+        change => panic!("no new tag: {:?}", change),
+    }
+}
+
+#[test]
+fn self_test_passes() {
+    let (reader, emulator) = setup();
+    assert_eq!(reader.self_test(), SelfTest::Passed(0x92));
+    assert!(emulator.borrow().antenna_on());
+}
+
+#[test]
+fn broken_self_test_is_reported() {
+    let emulator = Rc::new(RefCell::new(Mfrc522Emulator::new()));
+    emulator.borrow_mut().break_self_test();
+    let reader = TagReader::new(Spi(emulator.clone()), ChipSelect).unwrap();
+    assert_eq!(reader.self_test(), SelfTest::Failed(0x92));
+}
+
+#[test]
+fn unknown_version_is_configured() {
+    let emulator = Rc::new(RefCell::new(Mfrc522Emulator::new()));
+    emulator.borrow_mut().set_version(0x12);
+    let mut reader = TagReader::new(Spi(emulator.clone()), ChipSelect).unwrap();
+    assert_eq!(reader.self_test(), SelfTest::Untested(0x12));
+    assert!(emulator.borrow().antenna_on());
+    assert_eq!(emulator.borrow().antenna_gain(), 0b100);
+    assert_eq!(emulator.borrow().register(T_RELOAD_LOW), 0xE8);
+
+    emulator
+        .borrow_mut()
+        .place_tag(Tag::mifare_classic(CLASSIC_UID));
+    let uid = new_tag(&mut reader);
+    assert_eq!(uid.as_bytes(), &CLASSIC_UID);
+}
+
+#[test]
+fn tag_is_detected_once() {
+    let (mut reader, emulator) = setup();
+    assert_eq!(reader.check_for_tag(), None);
+
+    emulator
+        .borrow_mut()
+        .place_tag(Tag::mifare_classic(CLASSIC_UID));
+    let uid = new_tag(&mut reader);
+    assert_eq!(uid.as_bytes(), &CLASSIC_UID);
+    assert!(reader.has_tag());
+
+    // The same tag is not reported again while it stays on the reader
+    assert_eq!(reader.check_for_tag(), None);
+    assert_eq!(reader.check_for_tag(), None);
+}
+
+#[test]
+fn another_tag_is_new() {
+    let (mut reader, emulator) = setup();
+    emulator
+        .borrow_mut()
+        .place_tag(Tag::mifare_classic(CLASSIC_UID));
+    new_tag(&mut reader);
+
+    emulator.borrow_mut().place_tag(Tag::ntag(NTAG_UID));
+    let uid = new_tag(&mut reader);
+    assert_eq!(uid.as_bytes(), &NTAG_UID);
+}
+
+#[test]
+fn removal_needs_two_missed_polls() {
+    let (mut reader, emulator) = setup();
+    emulator
+        .borrow_mut()
+        .place_tag(Tag::mifare_classic(CLASSIC_UID));
+    new_tag(&mut reader);
+
+    emulator.borrow_mut().remove_tag();
+    assert_eq!(reader.check_for_tag(), None);
+    assert_eq!(reader.check_for_tag(), Some(TagChange::Removed));
+    assert!(!reader.has_tag());
+    assert_eq!(reader.check_for_tag(), None);
+}
+
+#[test]
+fn single_lost_answer_does_not_remove_the_tag() {
+    let (mut reader, emulator) = setup();
+    emulator
+        .borrow_mut()
+        .place_tag(Tag::mifare_classic(CLASSIC_UID));
+    new_tag(&mut reader);
+
+    emulator.borrow_mut().lose_answers(1);
+    assert_eq!(reader.check_for_tag(), None);
+    assert_eq!(reader.check_for_tag(), None);
+    assert!(reader.has_tag());
+}
+
+#[test]
+fn mifare_classic_is_read() {
+    let (mut reader, emulator) = setup();
+    let tag = Tag::mifare_classic(CLASSIC_UID).with_data(DEFAULT_CARD_BLOCK, card().into());
+    emulator.borrow_mut().place_tag(tag);
+
+    let uid = new_tag(&mut reader);
+    assert_eq!(reader.read_card(uid), Ok(card()));
+}
+
+#[test]
+fn mifare_classic_is_read_with_second_key() {
+    let (mut reader, emulator) = setup();
+    let key = [0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5];
+    let mut trailer = [0xFF; 16];
+    trailer[..6].copy_from_slice(&key);
+    trailer[6..10].copy_from_slice(&[0xFF, 0x07, 0x80, 0x69]);
+    let tag = Tag::mifare_classic(CLASSIC_UID)
+        .with_data(DEFAULT_CARD_BLOCK, card().into())
+        .with_data(7, trailer);
+    emulator.borrow_mut().place_tag(tag);
+    reader.set_keys(&[CardKey::DEFAULT, CardKey::new(KeyType::A, key)]);
+
+    let uid = new_tag(&mut reader);
+    assert_eq!(reader.read_card(uid), Ok(card()));
+}
+
+#[test]
+fn ntag_is_read() {
+    let (mut reader, emulator) = setup();
+    emulator
+        .borrow_mut()
+        .place_tag(Tag::ntag(NTAG_UID).with_data(4, card().into()));
+
+    let uid = new_tag(&mut reader);
+    assert_eq!(reader.read_card(uid), Ok(card()));
+}
+
+/// Read commands that have been sent to the tag
+fn reads(emulator: &Rc<RefCell<Mfrc522Emulator>>) -> Vec<Vec<u8>> {
+    emulator
+        .borrow()
+        .frames()
+        .iter()
+        .filter(|frame| frame.first() == Some(&0x30))
+        .cloned()
+        .collect()
+}
+
+#[test]
+fn tag_type_is_known_from_the_atqa() {
+    let (mut reader, emulator) = setup();
+    emulator
+        .borrow_mut()
+        .place_tag(Tag::mifare_classic(CLASSIC_UID));
+    let uid = new_tag(&mut reader);
+    reader.set_keys(&[CardKey::new(KeyType::A, [0x01; 6])]);
+
+    // Only the MIFARE Classic read is tried, not the NTAG read without authentication
+    assert_eq!(reader.read_card(uid), Err(TagReaderError::Authentication));
+    assert!(reads(&emulator).is_empty());
+
+    emulator.borrow_mut().place_tag(Tag::ntag(NTAG_UID));
+    let uid = new_tag(&mut reader);
+    emulator.borrow_mut().fail_reads(1);
+    assert_eq!(reader.read_card(uid), Err(TagReaderError::Timeout));
+    assert_eq!(reads(&emulator).len(), 1);
+}
+
+#[test]
+fn empty_card_has_bad_cookie() {
+    let (mut reader, emulator) = setup();
+    emulator
+        .borrow_mut()
+        .place_tag(Tag::mifare_classic(CLASSIC_UID));
+
+    let uid = new_tag(&mut reader);
+    assert_eq!(reader.read_card(uid), Err(TagReaderError::BadCookie));
+    assert_eq!(reader.error_counters().bad_cookie, 1);
+}
+
+#[test]
+fn unknown_key_fails_authentication() {
+    let (mut reader, emulator) = setup();
+    emulator
+        .borrow_mut()
+        .place_tag(Tag::mifare_classic(CLASSIC_UID).with_data(DEFAULT_CARD_BLOCK, card().into()));
+    reader.set_keys(&[CardKey::new(KeyType::A, [0x01; 6])]);
+
+    let uid = new_tag(&mut reader);
+    assert_eq!(reader.read_card(uid), Err(TagReaderError::Authentication));
+    assert_eq!(reader.error_counters().authentication, 1);
+}
+
+#[test]
+fn failed_read_is_counted() {
+    let (mut reader, emulator) = setup();
+    emulator
+        .borrow_mut()
+        .place_tag(Tag::mifare_classic(CLASSIC_UID).with_data(DEFAULT_CARD_BLOCK, card().into()));
+
+    let uid = new_tag(&mut reader);
+    emulator.borrow_mut().fail_reads(1);
+    assert_eq!(reader.read_card(uid), Err(TagReaderError::Timeout));
+    assert_eq!(reader.error_counters().timeout, 1);
+
+    // The next read works again
+    assert_eq!(reader.read_card(uid), Ok(card()));
+}
+
+#[test]
+fn spi_failures_reset_the_reader() {
+    let (mut reader, emulator) = setup();
+    let resets = emulator.borrow().resets();
+
+    emulator.borrow_mut().fail_transfers(3);
+    for _ in 0..3 {
+        assert_eq!(reader.check_for_tag(), None);
+    }
+    assert!(reader.error_counters().spi >= 3);
+    assert_eq!(reader.error_counters().recoveries, 1);
+    assert!(emulator.borrow().resets() > resets);
+    assert!(emulator.borrow().antenna_on());
+}
+
+#[test]
+fn reader_is_configured_again_after_brownout() {
+    let (mut reader, emulator) = setup();
+    emulator.borrow_mut().brownout();
+    assert!(!emulator.borrow().antenna_on());
+
+    emulator
+        .borrow_mut()
+        .place_tag(Tag::mifare_classic(CLASSIC_UID));
+    for _ in 0..3 {
+        assert_eq!(reader.check_for_tag(), None);
+    }
+    assert_eq!(reader.error_counters().recoveries, 1);
+    assert!(emulator.borrow().antenna_on());
+    new_tag(&mut reader);
+}
+
+const STATUS2: u8 = 0x08;
+const MF_CRYPTO1_ON: u8 = 0x08;
+const ADMIN_KEY: [u8; 6] = [0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC];
+
+fn crypto_on(emulator: &Rc<RefCell<Mfrc522Emulator>>) -> bool {
+    emulator.borrow().register(STATUS2) & MF_CRYPTO1_ON != 0
+}
+
+#[test]
+fn mifare_classic_is_written() {
+    let (mut reader, emulator) = setup();
+    emulator
+        .borrow_mut()
+        .place_tag(Tag::mifare_classic(CLASSIC_UID));
+
+    let uid = new_tag(&mut reader);
+    assert_eq!(reader.write_card(uid, card(), false), Ok(()));
+    assert!(!crypto_on(&emulator));
+    let data = emulator.borrow().tag().unwrap().data(DEFAULT_CARD_BLOCK);
+    assert_eq!(data, <[u8; 16]>::from(card()));
+    assert_eq!(reader.read_card(uid), Ok(card()));
+}
+
+#[test]
+fn card_block_outside_of_1k_cards_is_ignored() {
+    let (mut reader, emulator) = setup();
+    reader.set_card_block(8);
+    // Block 129 is a data block of a 4K card
+    reader.set_card_block(129);
+    emulator
+        .borrow_mut()
+        .place_tag(Tag::mifare_classic(CLASSIC_UID));
+
+    let uid = new_tag(&mut reader);
+    assert_eq!(reader.write_card(uid, card(), false), Ok(()));
+    let data = emulator.borrow().tag().unwrap().data(8);
+    assert_eq!(data, <[u8; 16]>::from(card()));
+}
+
+#[test]
+fn ntag_is_written() {
+    let (mut reader, emulator) = setup();
+    emulator.borrow_mut().place_tag(Tag::ntag(NTAG_UID));
+
+    let uid = new_tag(&mut reader);
+    assert_eq!(reader.write_card(uid, card(), false), Ok(()));
+    assert_eq!(reader.read_card(uid), Ok(card()));
+}
+
+#[test]
+fn protect_without_admin_key_writes_nothing() {
+    let (mut reader, emulator) = setup();
+    emulator
+        .borrow_mut()
+        .place_tag(Tag::mifare_classic(CLASSIC_UID));
+
+    let uid = new_tag(&mut reader);
+    assert_eq!(
+        reader.write_card(uid, card(), true),
+        Err(WriteError::Protect)
+    );
+    assert!(!crypto_on(&emulator));
+    let data = emulator.borrow().tag().unwrap().data(DEFAULT_CARD_BLOCK);
+    assert_eq!(data, [0; 16]);
+}
+
+#[test]
+fn protected_card_needs_the_admin_key() {
+    let (mut reader, emulator) = setup();
+    emulator
+        .borrow_mut()
+        .place_tag(Tag::mifare_classic(CLASSIC_UID));
+    reader.set_admin_key(Some(ADMIN_KEY));
+
+    let uid = new_tag(&mut reader);
+    assert_eq!(reader.write_card(uid, card(), true), Ok(()));
+    assert!(!crypto_on(&emulator));
+    let trailer = emulator
+        .borrow()
+        .tag()
+        .unwrap()
+        .data(DEFAULT_CARD_BLOCK | 0x03);
+    assert_eq!(trailer[6..9], [0x78, 0x77, 0x88]);
+    assert_eq!(trailer[10..], ADMIN_KEY);
+
+    // Other boxes still read the card, but can not write it
+    reader.set_admin_key(None);
+    assert_eq!(reader.read_card(uid), Ok(card()));
+    assert_eq!(
+        reader.write_card(uid, Card::new(1, 1, 0, 0), false),
+        Err(WriteError::Write)
+    );
+    assert!(!crypto_on(&emulator));
+    assert_eq!(reader.read_card(uid), Ok(card()));
+
+    // The admin key unlocks it again
+    reader.set_admin_key(Some(ADMIN_KEY));
+    assert_eq!(reader.unlock_card(uid), Ok(()));
+    assert!(!crypto_on(&emulator));
+    let trailer = emulator
+        .borrow()
+        .tag()
+        .unwrap()
+        .data(DEFAULT_CARD_BLOCK | 0x03);
+    assert_eq!(trailer[6..9], [0xFF, 0x07, 0x80]);
+}
+
+#[test]
+fn failed_write_stops_the_crypto() {
+    let (mut reader, emulator) = setup();
+    emulator
+        .borrow_mut()
+        .place_tag(Tag::mifare_classic(CLASSIC_UID));
+
+    let uid = new_tag(&mut reader);
+    // The read back for the verification is lost
+    emulator.borrow_mut().fail_reads(1);
+    assert_eq!(
+        reader.write_card(uid, card(), false),
+        Err(WriteError::Verify)
+    );
+    assert!(!crypto_on(&emulator));
+
+    // The reader is not deaf afterwards
+    assert_eq!(reader.read_card(uid), Ok(card()));
+}
+
+const T_RELOAD_LOW: u8 = 0x2D;
+
+#[test]
+fn irq_pin_is_released_without_detection() {
+    let (_reader, emulator) = setup();
+    assert!(!emulator.borrow().irq_low());
+}
+
+#[test]
+fn detection_without_tag_expires_and_powers_down() {
+    let (mut reader, emulator) = setup();
+    reader.arm_detection();
+    // The timer of the MFRC522 ends the wait for an answer
+    assert!(emulator.borrow().irq_low());
+    assert_eq!(emulator.borrow().register(T_RELOAD_LOW), 40);
+
+    assert!(!reader.disarm_detection());
+    assert!(!emulator.borrow().irq_low());
+    assert_eq!(emulator.borrow().register(T_RELOAD_LOW), 0xE8);
+
+    reader.power_down();
+    assert!(emulator.borrow().is_powered_down());
+    assert!(!emulator.borrow().antenna_on());
+    assert_eq!(reader.error_counters().recoveries, 0);
+}
+
+#[test]
+fn detection_wakes_the_reader_up() {
+    let (mut reader, emulator) = setup();
+    reader.power_down();
+    emulator
+        .borrow_mut()
+        .place_tag(Tag::mifare_classic(CLASSIC_UID));
+
+    reader.arm_detection();
+    assert!(!emulator.borrow().is_powered_down());
+    assert!(emulator.borrow().antenna_on());
+    assert!(emulator.borrow().irq_low());
+    assert!(reader.disarm_detection());
+
+    let uid = new_tag(&mut reader);
+    assert_eq!(uid.as_bytes(), &CLASSIC_UID);
+    assert_eq!(reader.error_counters().spi, 0);
+}
+
+#[test]
+fn commands_of_the_driver_end_the_detection() {
+    let (mut reader, emulator) = setup();
+    reader.arm_detection();
+    reader.power_down();
+    emulator
+        .borrow_mut()
+        .place_tag(Tag::ntag(NTAG_UID).with_data(4, card().into()));
+
+    // Without the IRQ pin the tag is still found by polling
+    let uid = new_tag(&mut reader);
+    assert_eq!(reader.read_card(uid), Ok(card()));
+    assert!(!emulator.borrow().irq_low());
+    assert_eq!(emulator.borrow().register(T_RELOAD_LOW), 0xE8);
+    assert_eq!(reader.error_counters().recoveries, 0);
+}
+
+#[test]
+fn failed_detection_is_counted() {
+    let (mut reader, emulator) = setup();
+    emulator.borrow_mut().fail_transfers(1);
+    reader.arm_detection();
+    assert_eq!(reader.error_counters().spi, 1);
+
+    // The next detection works again
+    emulator
+        .borrow_mut()
+        .place_tag(Tag::mifare_classic(CLASSIC_UID));
+    reader.arm_detection();
+    assert!(reader.disarm_detection());
+}
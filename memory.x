@@ -1,6 +1,7 @@
 /* Linker script for the STM32F103C8T6 */
 MEMORY
 {
-  FLASH : ORIGIN = 0x08000000, LENGTH = 64K
+  /* The last two pages are reserved for the tag mapping and the settings */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 62K
   RAM : ORIGIN = 0x20000000, LENGTH = 20K
 }
\ No newline at end of file
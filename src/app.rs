@@ -1,12 +1,40 @@
+use crate::player::{Equalizer, PlaybackDevice};
+use crate::settings::Settings;
+pub use music_box_drivers::card::Card;
+
 /// Messages for task communication
 #[derive(Debug)]
 pub enum Events {
     /// A new tag is in the field of the tag reader
     NewTag(Card),
+    /// The tag has been removed from the field of the tag reader
+    TagRemoved,
     /// A button has been pressed long (>1s)
     ButtonPressedShort(Button),
     /// A button has been pressed short
     ButtonPressedLong(Button),
+    /// The DFPlayer finished the track with the given number
+    TrackEnded(u16),
+    /// A SD card has been inserted into the DFPlayer
+    SdInserted,
+    /// The SD card has been removed from the DFPlayer
+    SdRemoved,
+    /// The DFPlayer started without a SD card
+    NoSdCard,
+    /// Number of tracks in a folder (folder, tracks), 0 if the DFPlayer did not answer
+    FolderTracks(u8, u16),
+    /// The DFPlayer reported an error
+    PlayerError(u8),
+    /// The DFPlayer did not acknowledge a command
+    PlayerNotResponding,
+    /// The time of the sleep timer is over
+    SleepTimerExpired,
+    /// A card has been programmed, false if writing failed
+    CardWritten(bool),
+    /// The mapping of a tag has been changed and has to be stored, false if it failed
+    TagMapped(bool),
+    /// Outcome of the self-test of the tag reader at startup
+    ReaderSelfTest(crate::tagreader::SelfTest),
 }
 
 #[derive(Debug, Copy, Clone, PartialOrd, PartialEq)]
@@ -17,41 +45,19 @@ pub enum Button {
     Down,
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
-pub struct Card {
-    cookie: u32,
-    version: u8,
-    folder: u8,
-    mode: u8,
-    special1: u8,
-    special2: u8,
-}
-
-impl core::convert::TryFrom<[u8; 16]> for Card {
-    type Error = ();
-
-    fn try_from(value: [u8; 16]) -> Result<Self, Self::Error> {
-        // Check the cookie
-        let cookie = u32::from_be_bytes([value[0], value[1], value[2], value[3]]);
-        if cookie != 0x1337B347 {
-            return Err(());
-        }
-
-        // Encode the rest
-        let version = value[4];
-        let folder = value[5];
-        let mode = value[6];
-        let special1 = value[7];
-        let special2 = value[8];
-
-        Ok(Self {
-            cookie,
-            version,
-            folder,
-            mode,
-            special1,
-            special2,
-        })
+impl From<Modus> for Card {
+    fn from(modus: Modus) -> Self {
+        let (mode, folder, special1, special2) = match modus {
+            Modus::RandomSingle(folder) => (0x01, folder, 0, 0),
+            Modus::AlbumNormal(folder) => (0x02, folder, 0, 0),
+            Modus::AlbumShuffel(folder) => (0x03, folder, 0, 0),
+            Modus::Single(folder, track) => (0x04, folder, track, 0),
+            Modus::AlbumSave(folder) => (0x05, folder, 0, 0),
+            Modus::RandomStartToEndSingle(folder, start, end) => (0x07, folder, start, end),
+            Modus::StartToEndAlbum(folder, start, end) => (0x08, folder, start, end),
+            Modus::RandomStartToEnd(folder, start, end) => (0x09, folder, start, end),
+        };
+        Card::new(folder, mode, special1, special2)
     }
 }
 
@@ -76,11 +82,32 @@ pub enum Modus {
     RandomStartToEnd(u8, u8, u8),
 }
 
+impl Modus {
+    /// Folder the mode plays from
+    pub fn folder(&self) -> u8 {
+        match *self {
+            Modus::RandomSingle(folder)
+            | Modus::AlbumNormal(folder)
+            | Modus::AlbumShuffel(folder)
+            | Modus::Single(folder, _)
+            | Modus::AlbumSave(folder)
+            | Modus::RandomStartToEndSingle(folder, _, _)
+            | Modus::StartToEndAlbum(folder, _, _)
+            | Modus::RandomStartToEnd(folder, _, _) => folder,
+        }
+    }
+}
+
 impl core::convert::TryFrom<Card> for Modus {
     type Error = ();
 
     fn try_from(value: Card) -> Result<Self, Self::Error> {
-        Modus::try_from((value.mode, value.folder, value.special1, value.special2))
+        Modus::try_from((
+            value.mode(),
+            value.folder(),
+            value.special1(),
+            value.special2(),
+        ))
     }
 }
 
@@ -118,7 +145,12 @@ impl core::convert::TryFrom<Card> for Modifyer {
     type Error = ();
 
     fn try_from(value: Card) -> Result<Self, Self::Error> {
-        Modifyer::try_from((value.mode, value.folder, value.special1, value.special2))
+        Modifyer::try_from((
+            value.mode(),
+            value.folder(),
+            value.special1(),
+            value.special2(),
+        ))
     }
 }
 
@@ -126,16 +158,656 @@ impl core::convert::TryFrom<(u8, u8, u8, u8)> for Modifyer {
     type Error = ();
 
     fn try_from(value: (u8, u8, u8, u8)) -> Result<Self, Self::Error> {
+        // Modifyer cards have no folder, otherwise it is a card with music
         match value {
             (0x00, _, _, _) => Ok(Modifyer::None),
             (0xff, _, _, _) => Ok(Modifyer::AdminMenu),
-            (0x01, _, min, _) => Ok(Modifyer::SleepTimer(min)),
-            (0x02, _, _, _) => Ok(Modifyer::FreezeDance),
-            (0x03, _, _, _) => Ok(Modifyer::Locked),
-            (0x04, _, _, _) => Ok(Modifyer::Toddler),
-            (0x05, _, _, _) => Ok(Modifyer::Kindergarden),
-            (0x06, _, _, _) => Ok(Modifyer::RepeatSingle),
+            (0x01, 0, min, _) => Ok(Modifyer::SleepTimer(min)),
+            (0x02, 0, _, _) => Ok(Modifyer::FreezeDance),
+            (0x03, 0, _, _) => Ok(Modifyer::Locked),
+            (0x04, 0, _, _) => Ok(Modifyer::Toddler),
+            (0x05, 0, _, _) => Ok(Modifyer::Kindergarden),
+            (0x06, 0, _, _) => Ok(Modifyer::RepeatSingle),
             _ => Err(()),
         }
     }
 }
+
+/// Simple xorshift random number generator
+pub struct Random {
+    state: u32,
+}
+
+impl Random {
+    pub fn new(seed: u32) -> Self {
+        // The state must never be zero
+        Self { state: seed | 1 }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+
+    /// Random number in the range `0..n`
+    pub fn below(&mut self, n: u32) -> u32 {
+        if n == 0 {
+            0
+        } else {
+            self.next_u32() % n
+        }
+    }
+}
+
+/// Tracks that are played for a card
+pub struct Playlist {
+    folder: u8,
+    tracks: heapless::Vec<u8, heapless::consts::U255>,
+    position: usize,
+}
+
+impl Playlist {
+    /// Create the playlist for a mode. `track_count` is the number of tracks in the folder.
+    pub fn new(mode: Modus, track_count: u16, random: &mut Random) -> Self {
+        let last = track_count.min(u16::from(u8::MAX)) as u8;
+        let mut playlist = Self {
+            folder: mode.folder(),
+            tracks: heapless::Vec::new(),
+            position: 0,
+        };
+
+        match mode {
+            Modus::Single(_, track) => playlist.push_range(track, track),
+            Modus::RandomSingle(_) => playlist.push_random(1, last, random),
+            Modus::AlbumNormal(_) | Modus::AlbumSave(_) => playlist.push_range(1, last),
+            Modus::AlbumShuffel(_) => {
+                playlist.push_range(1, last);
+                playlist.shuffle(random);
+            }
+            Modus::RandomStartToEndSingle(_, start, end) => {
+                playlist.push_random(start, end.min(last), random)
+            }
+            Modus::StartToEndAlbum(_, start, end) => playlist.push_range(start, end.min(last)),
+            Modus::RandomStartToEnd(_, start, end) => {
+                playlist.push_range(start, end.min(last));
+                playlist.shuffle(random);
+            }
+        }
+
+        playlist
+    }
+
+    fn push_range(&mut self, start: u8, end: u8) {
+        for track in start.max(1)..=end {
+            self.tracks.push(track).ok();
+        }
+    }
+
+    fn push_random(&mut self, start: u8, end: u8, random: &mut Random) {
+        let start = start.max(1);
+        if start <= end {
+            let offset = random.below(u32::from(end - start) + 1) as u8;
+            self.tracks.push(start + offset).ok();
+        }
+    }
+
+    /// Fisher-Yates shuffle of the tracks
+    fn shuffle(&mut self, random: &mut Random) {
+        for i in (1..self.tracks.len()).rev() {
+            let j = random.below(i as u32 + 1) as usize;
+            self.tracks.swap(i, j);
+        }
+    }
+
+    /// Folder of the tracks
+    pub fn folder(&self) -> u8 {
+        self.folder
+    }
+
+    /// Track that is played at the moment
+    pub fn current(&self) -> Option<u8> {
+        self.tracks.get(self.position).copied()
+    }
+
+    /// Folder and track that is played at the moment
+    pub fn current_folder_track(&self) -> Option<(u8, u8)> {
+        self.current().map(|track| (self.folder, track))
+    }
+
+    /// Advance to the next track. Returns None at the end of the playlist
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<u8> {
+        if self.position + 1 < self.tracks.len() {
+            self.position += 1;
+            self.current()
+        } else {
+            None
+        }
+    }
+
+    /// Go back to the previous track. Stays at the first track
+    pub fn previous(&mut self) -> Option<u8> {
+        self.position = self.position.saturating_sub(1);
+        self.current()
+    }
+}
+
+/// Spoken prompts. The number is the file in the `ADVERT` and the `mp3` folder on the SD card.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Announcement {
+    VolumeMax,
+    VolumeMin,
+    CardUnknown,
+    SleepTimerSet,
+    FreezeDance,
+    Locked,
+    Toddler,
+    Kindergarden,
+    RepeatSingle,
+    AdminMenu,
+    Folder,
+    Track,
+    MenuEqualizer,
+    MenuPlaybackDevice,
+    Saved,
+    MenuExit,
+    MenuProgramCard,
+    PlaceCard,
+    CardWritten,
+    CardWriteFailed,
+    Equalizer(Equalizer),
+    PlaybackDevice(PlaybackDevice),
+    /// Mode byte of a card
+    Mode(u8),
+    MenuStayOnBox,
+    Off,
+    On,
+    MenuCardBlock,
+    MenuCardKey,
+    MenuProtectCard,
+    MenuUnlockCard,
+    MenuMapTag,
+    MenuUnmapTag,
+    ReaderDefect,
+}
+
+impl Announcement {
+    /// Number of the prompt file
+    pub fn track(&self) -> u16 {
+        match *self {
+            Announcement::VolumeMax => 500,
+            Announcement::VolumeMin => 501,
+            Announcement::CardUnknown => 502,
+            Announcement::SleepTimerSet => 503,
+            Announcement::FreezeDance => 504,
+            Announcement::Locked => 505,
+            Announcement::Toddler => 506,
+            Announcement::Kindergarden => 507,
+            Announcement::RepeatSingle => 508,
+            Announcement::AdminMenu => 509,
+            Announcement::Folder => 510,
+            Announcement::Track => 511,
+            Announcement::MenuEqualizer => 512,
+            Announcement::MenuPlaybackDevice => 513,
+            Announcement::Saved => 514,
+            Announcement::MenuExit => 515,
+            Announcement::MenuProgramCard => 516,
+            Announcement::PlaceCard => 517,
+            Announcement::CardWritten => 518,
+            Announcement::CardWriteFailed => 519,
+            Announcement::Equalizer(equalizer) => 520 + equalizer as u16,
+            Announcement::PlaybackDevice(device) => 530 + device as u16,
+            Announcement::Mode(mode) => 540 + u16::from(mode),
+            Announcement::MenuStayOnBox => 550,
+            Announcement::Off => 551,
+            Announcement::On => 552,
+            Announcement::MenuCardBlock => 553,
+            Announcement::MenuCardKey => 554,
+            Announcement::MenuProtectCard => 555,
+            Announcement::MenuUnlockCard => 556,
+            Announcement::MenuMapTag => 557,
+            Announcement::MenuUnmapTag => 558,
+            Announcement::ReaderDefect => 559,
+        }
+    }
+}
+
+impl Modifyer {
+    /// Prompt that confirms the modifyer card
+    pub fn announcement(&self) -> Option<Announcement> {
+        match self {
+            Modifyer::None => None,
+            Modifyer::AdminMenu => Some(Announcement::AdminMenu),
+            Modifyer::SleepTimer(_) => Some(Announcement::SleepTimerSet),
+            Modifyer::FreezeDance => Some(Announcement::FreezeDance),
+            Modifyer::Locked => Some(Announcement::Locked),
+            Modifyer::Toddler => Some(Announcement::Toddler),
+            Modifyer::Kindergarden => Some(Announcement::Kindergarden),
+            Modifyer::RepeatSingle => Some(Announcement::RepeatSingle),
+        }
+    }
+}
+
+/// Playback that is replaced by prompts from the `mp3` folder
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Interrupted {
+    pub folder: u8,
+    pub track: u8,
+    /// The track was playing, otherwise it was paused
+    pub playing: bool,
+}
+
+/// Plays prompts from the `mp3` folder one after another and remembers the playback that
+/// is replaced by them.
+///
+/// While a track is playing the DFPlayer plays single announcements from the `ADVERT` folder
+/// and continues the track by itself. Otherwise the prompts are played from the `mp3` folder,
+/// which ends the current track. The track is started again afterwards (and paused if it was
+/// paused before).
+#[derive(Debug, Default)]
+pub struct Announcer {
+    /// Prompts of the running sequence
+    prompts: heapless::Vec<u16, heapless::consts::U8>,
+    /// Prompt that is playing
+    position: usize,
+    /// Prompts from the `mp3` folder are playing
+    active: bool,
+    /// Playback that has to be restored afterwards
+    resume: Option<Interrupted>,
+}
+
+impl Announcer {
+    /// Prompts from the `mp3` folder are playing
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Start a sequence of prompts from the `mp3` folder. A running sequence is replaced.
+    /// Returns the first prompt to play.
+    pub fn start(&mut self, prompts: &[u16], interrupted: Option<Interrupted>) -> Option<u16> {
+        // Keep the playback of a running sequence
+        if !self.active {
+            self.resume = interrupted;
+        }
+        self.prompts.clear();
+        self.prompts
+            .extend_from_slice(&prompts[..prompts.len().min(self.prompts.capacity())])
+            .ok();
+        self.position = 0;
+        self.active = !self.prompts.is_empty();
+        self.prompts.first().copied()
+    }
+
+    /// The current prompt has finished. Returns the next prompt of the sequence
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<u16> {
+        self.position += 1;
+        self.prompts.get(self.position).copied()
+    }
+
+    /// The sequence has ended. Returns the playback that has to be restored
+    pub fn end(&mut self) -> Option<Interrupted> {
+        self.active = false;
+        self.resume.take()
+    }
+
+    /// Forget the interrupted playback, e.g. because a new card has been placed
+    pub fn cancel(&mut self) {
+        self.active = false;
+        self.resume = None;
+    }
+}
+
+/// Prompts in the `mp3` folder that speak a word followed by a number, e.g. "folder 17"
+pub fn labeled_number_prompts(
+    label: Announcement,
+    number: u16,
+) -> heapless::Vec<u16, heapless::consts::U8> {
+    let mut prompts = heapless::Vec::new();
+    prompts.push(label.track()).ok();
+    prompts
+        .extend_from_slice(&crate::prompts::number_prompts(number))
+        .ok();
+    prompts
+}
+
+/// What happens when a fade has finished
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AfterFade {
+    /// Keep playing
+    Nothing,
+    /// Pause the track and restore the given volume
+    Pause(u8),
+}
+
+/// Linear volume ramp over a duration in milliseconds
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Fade {
+    from: u8,
+    to: u8,
+    duration_ms: u32,
+    elapsed_ms: u32,
+    after: AfterFade,
+}
+
+impl Fade {
+    pub fn new(from: u8, to: u8, duration_ms: u32, after: AfterFade) -> Self {
+        Self {
+            from,
+            to,
+            duration_ms,
+            elapsed_ms: 0,
+            after,
+        }
+    }
+
+    /// Advance the fade. Returns the volume for the new point in time
+    pub fn step(&mut self, step_ms: u32) -> u8 {
+        self.elapsed_ms = self
+            .elapsed_ms
+            .saturating_add(step_ms)
+            .min(self.duration_ms);
+        self.volume()
+    }
+
+    /// Volume for the current point in time
+    pub fn volume(&self) -> u8 {
+        if self.duration_ms == 0 {
+            return self.to;
+        }
+        let from = i64::from(self.from);
+        let to = i64::from(self.to);
+        let volume = from + (to - from) * i64::from(self.elapsed_ms) / i64::from(self.duration_ms);
+        volume as u8
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.elapsed_ms >= self.duration_ms
+    }
+
+    pub fn after(&self) -> AfterFade {
+        self.after
+    }
+}
+
+/// Items of the admin menu
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MenuItem {
+    Equalizer,
+    PlaybackDevice,
+    /// The card has to stay on the box while playing
+    StayOnBox,
+    /// Block with the card data on MIFARE Classic tags
+    CardBlock,
+    /// Key that is tried first for MIFARE Classic tags
+    CardKey,
+    /// Folder of the card that is programmed
+    ProgramFolder,
+    /// Mode of the card that is programmed
+    ProgramMode,
+    /// Protect the programmed card against rewriting
+    ProgramProtect,
+    /// Reset a protected card to the transport keys
+    UnlockCard,
+    /// Play the selected folder and mode for a tag that can not be programmed
+    MapTag,
+    /// Forget the mapping of a tag
+    UnmapTag,
+}
+
+impl MenuItem {
+    fn next(self) -> Self {
+        match self {
+            MenuItem::Equalizer => MenuItem::PlaybackDevice,
+            MenuItem::PlaybackDevice => MenuItem::StayOnBox,
+            MenuItem::StayOnBox => MenuItem::CardBlock,
+            MenuItem::CardBlock => MenuItem::CardKey,
+            MenuItem::CardKey => MenuItem::ProgramFolder,
+            MenuItem::ProgramFolder => MenuItem::ProgramMode,
+            MenuItem::ProgramMode => MenuItem::ProgramProtect,
+            MenuItem::ProgramProtect => MenuItem::UnlockCard,
+            MenuItem::UnlockCard => MenuItem::MapTag,
+            MenuItem::MapTag => MenuItem::UnmapTag,
+            MenuItem::UnmapTag => MenuItem::Equalizer,
+        }
+    }
+
+    fn previous(self) -> Self {
+        match self {
+            MenuItem::Equalizer => MenuItem::UnmapTag,
+            MenuItem::PlaybackDevice => MenuItem::Equalizer,
+            MenuItem::StayOnBox => MenuItem::PlaybackDevice,
+            MenuItem::CardBlock => MenuItem::StayOnBox,
+            MenuItem::CardKey => MenuItem::CardBlock,
+            MenuItem::ProgramFolder => MenuItem::CardKey,
+            MenuItem::ProgramMode => MenuItem::ProgramFolder,
+            MenuItem::ProgramProtect => MenuItem::ProgramMode,
+            MenuItem::UnlockCard => MenuItem::ProgramProtect,
+            MenuItem::MapTag => MenuItem::UnlockCard,
+            MenuItem::UnmapTag => MenuItem::MapTag,
+        }
+    }
+}
+
+/// Modes that can be programmed from the admin menu. They only need a folder.
+const PROGRAM_MODES: [fn(u8) -> Modus; 4] = [
+    Modus::AlbumNormal,
+    Modus::AlbumShuffel,
+    Modus::RandomSingle,
+    Modus::AlbumSave,
+];
+
+/// What is done with the next tag that is placed on the box
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Programming {
+    /// Write the card, protect it against rewriting if true
+    Write(Card, bool),
+    /// Reset the card to the transport keys
+    Unlock,
+    /// Remember the card for the UID of the tag instead of writing it
+    Map(Card),
+    /// Forget the card for the UID of the tag
+    Unmap,
+}
+
+/// What the application has to do after a button press in the admin menu
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AdminAction {
+    /// Tell the selected menu item or value
+    Announce(Announcement),
+    /// Tell a value with a number
+    AnnounceNumber(Announcement, u16),
+    /// Apply the changed settings to listen to them
+    Preview(Settings, Announcement),
+    /// Apply the changed settings and tell the value with a number
+    PreviewNumber(Settings, Announcement, u16),
+    /// Store the settings and leave the menu
+    Save(Settings),
+    /// Restore the settings from before the menu and leave it
+    Cancel(Settings),
+    /// Program the next tag that is placed on the box
+    Program(Programming),
+}
+
+/// Menu to change the settings and program cards with the buttons. Long presses on Up and Down
+/// select the item, short presses change the value. A short press on PlayPause saves the
+/// settings or programs a card with the selected folder and mode, a long press cancels.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct AdminMenu {
+    item: MenuItem,
+    original: Settings,
+    settings: Settings,
+    folder: u8,
+    mode: usize,
+    /// Protect programmed cards
+    protect: bool,
+    /// Number of keys the preferred key is selected from
+    key_count: u8,
+}
+
+impl AdminMenu {
+    pub fn new(settings: Settings, key_count: u8) -> Self {
+        Self {
+            item: MenuItem::Equalizer,
+            original: settings,
+            settings,
+            folder: 1,
+            mode: 0,
+            protect: false,
+            key_count,
+        }
+    }
+
+    /// Prompt of the selected menu item
+    pub fn item_announcement(&self) -> Announcement {
+        match self.item {
+            MenuItem::Equalizer => Announcement::MenuEqualizer,
+            MenuItem::PlaybackDevice => Announcement::MenuPlaybackDevice,
+            MenuItem::StayOnBox => Announcement::MenuStayOnBox,
+            MenuItem::CardBlock => Announcement::MenuCardBlock,
+            MenuItem::CardKey => Announcement::MenuCardKey,
+            MenuItem::ProgramFolder => Announcement::MenuProgramCard,
+            MenuItem::ProgramMode => self.mode_announcement(),
+            MenuItem::ProgramProtect => Announcement::MenuProtectCard,
+            MenuItem::UnlockCard => Announcement::MenuUnlockCard,
+            MenuItem::MapTag => Announcement::MenuMapTag,
+            MenuItem::UnmapTag => Announcement::MenuUnmapTag,
+        }
+    }
+
+    /// Card with the selected folder and mode
+    pub fn card(&self) -> Card {
+        Card::from(PROGRAM_MODES[self.mode](self.folder))
+    }
+
+    fn mode_announcement(&self) -> Announcement {
+        Announcement::Mode(self.card().mode())
+    }
+
+    /// React on a button press
+    pub fn button(&mut self, button: Button, long: bool) -> AdminAction {
+        let up = button == Button::Up;
+        match (button, long) {
+            (Button::Up, true) | (Button::Down, true) => {
+                self.item = if up {
+                    self.item.next()
+                } else {
+                    self.item.previous()
+                };
+                AdminAction::Announce(self.item_announcement())
+            }
+            (Button::Up, false) | (Button::Down, false) => match self.item {
+                MenuItem::Equalizer => {
+                    let equalizer = self.settings.equalizer;
+                    self.settings.equalizer = if up {
+                        equalizer.next()
+                    } else {
+                        equalizer.previous()
+                    };
+                    AdminAction::Preview(
+                        self.settings,
+                        Announcement::Equalizer(self.settings.equalizer),
+                    )
+                }
+                MenuItem::PlaybackDevice => {
+                    let device = self.settings.device;
+                    self.settings.device = if up { device.next() } else { device.previous() };
+                    AdminAction::Preview(
+                        self.settings,
+                        Announcement::PlaybackDevice(self.settings.device),
+                    )
+                }
+                MenuItem::StayOnBox => {
+                    self.settings.stay_on_box = !self.settings.stay_on_box;
+                    let announcement = if self.settings.stay_on_box {
+                        Announcement::On
+                    } else {
+                        Announcement::Off
+                    };
+                    AdminAction::Preview(self.settings, announcement)
+                }
+                MenuItem::CardBlock => {
+                    // Skip the blocks that can not hold data, the blocks of a 1K card wrap
+                    // around
+                    let blocks = crate::tagreader::CLASSIC_1K_BLOCKS;
+                    let mut block = self.settings.card_block;
+                    loop {
+                        block = if up {
+                            (block + 1) % blocks
+                        } else {
+                            (block + blocks - 1) % blocks
+                        };
+                        if crate::tagreader::is_data_block(block) {
+                            break;
+                        }
+                    }
+                    self.settings.card_block = block;
+                    AdminAction::PreviewNumber(
+                        self.settings,
+                        Announcement::MenuCardBlock,
+                        u16::from(block),
+                    )
+                }
+                MenuItem::CardKey => {
+                    let count = self.key_count.max(1);
+                    let key = self.settings.card_key;
+                    self.settings.card_key = if up {
+                        (key + 1) % count
+                    } else {
+                        (key + count - 1) % count
+                    };
+                    // Keys are counted from 1
+                    AdminAction::PreviewNumber(
+                        self.settings,
+                        Announcement::MenuCardKey,
+                        u16::from(self.settings.card_key) + 1,
+                    )
+                }
+                MenuItem::ProgramFolder => {
+                    // Folders 01 to 99
+                    self.folder = if up {
+                        self.folder % 99 + 1
+                    } else {
+                        (self.folder + 97) % 99 + 1
+                    };
+                    AdminAction::AnnounceNumber(Announcement::Folder, u16::from(self.folder))
+                }
+                MenuItem::ProgramMode => {
+                    let count = PROGRAM_MODES.len();
+                    self.mode = if up {
+                        (self.mode + 1) % count
+                    } else {
+                        (self.mode + count - 1) % count
+                    };
+                    AdminAction::Announce(self.mode_announcement())
+                }
+                MenuItem::ProgramProtect => {
+                    self.protect = !self.protect;
+                    AdminAction::Announce(if self.protect {
+                        Announcement::On
+                    } else {
+                        Announcement::Off
+                    })
+                }
+                MenuItem::UnlockCard => AdminAction::Announce(Announcement::MenuUnlockCard),
+                // The folder and mode are selected with the items of the programming
+                MenuItem::MapTag => AdminAction::Announce(Announcement::MenuMapTag),
+                MenuItem::UnmapTag => AdminAction::Announce(Announcement::MenuUnmapTag),
+            },
+            (Button::PlayPause, false) => match self.item {
+                MenuItem::ProgramFolder | MenuItem::ProgramMode | MenuItem::ProgramProtect => {
+                    AdminAction::Program(Programming::Write(self.card(), self.protect))
+                }
+                MenuItem::UnlockCard => AdminAction::Program(Programming::Unlock),
+                MenuItem::MapTag => AdminAction::Program(Programming::Map(self.card())),
+                MenuItem::UnmapTag => AdminAction::Program(Programming::Unmap),
+                _ => AdminAction::Save(self.settings),
+            },
+            (Button::PlayPause, true) => AdminAction::Cancel(self.original),
+        }
+    }
+}
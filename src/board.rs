@@ -0,0 +1,124 @@
+//! Connection of the drivers to the peripherals of the Blue Pill
+
+use stm32f1xx_hal::gpio::{Alternate, Edge, ExtiPin, Floating, Input, Output, PullUp, PushPull};
+use stm32f1xx_hal::spi::{Spi, Spi2NoRemap};
+
+use crate::{player, tagreader};
+use rtic::cyccnt::U32Ext;
+
+pub type PinSerialTx = stm32f1xx_hal::gpio::gpioa::PA9<Alternate<PushPull>>;
+pub type PinSerialRx = stm32f1xx_hal::gpio::gpioa::PA10<Input<Floating>>;
+pub type PinBusy = stm32f1xx_hal::gpio::gpiob::PB5<Input<Floating>>;
+pub type PinAmpEnable = stm32f1xx_hal::gpio::gpiob::PB6<Output<PushPull>>;
+
+pub type PinCS = stm32f1xx_hal::gpio::gpioa::PA4<Output<PushPull>>;
+pub type PinClock = stm32f1xx_hal::gpio::gpiob::PB13<Alternate<PushPull>>;
+pub type PinMiso = stm32f1xx_hal::gpio::gpiob::PB14<Input<Floating>>;
+pub type PinMosi = stm32f1xx_hal::gpio::gpiob::PB15<Alternate<PushPull>>;
+pub type PinIrq = stm32f1xx_hal::gpio::gpioa::PA3<Input<PullUp>>;
+
+pub type SpiDevice =
+    Spi<stm32f1xx_hal::device::SPI2, Spi2NoRemap, (PinClock, PinMiso, PinMosi), u8>;
+
+/// Tag reader on SPI2
+pub type TagReader = tagreader::TagReader<SpiDevice, PinCS>;
+
+pub type SerialDevice =
+    stm32f1xx_hal::serial::Serial<stm32f1xx_hal::device::USART1, (PinSerialTx, PinSerialRx)>;
+
+/// DFPlayer on USART1 timed by the cycle counter
+pub type Player = player::DFPlayer<SerialDevice, CycleClock>;
+
+/// Monotonic timer of RTIC as clock of the drivers
+pub struct CycleClock {
+    cycles_per_ms: u32,
+}
+
+impl CycleClock {
+    pub fn new(clocks: stm32f1xx_hal::rcc::Clocks) -> Self {
+        Self {
+            cycles_per_ms: clocks.sysclk().0 / 1000,
+        }
+    }
+}
+
+impl player::Clock for CycleClock {
+    type Instant = rtic::cyccnt::Instant;
+    type Duration = rtic::cyccnt::Duration;
+
+    fn now(&self) -> Self::Instant {
+        rtic::cyccnt::Instant::now()
+    }
+
+    fn millis(&self, ms: u32) -> Self::Duration {
+        (ms * self.cycles_per_ms).cycles()
+    }
+}
+
+/// Setup the DFPlayer on USART1
+pub fn player(
+    serial_hw: stm32f1xx_hal::device::USART1,
+    tx: PinSerialTx,
+    rx: PinSerialRx,
+    mapr: &mut stm32f1xx_hal::afio::MAPR,
+    clocks: stm32f1xx_hal::rcc::Clocks,
+    apb: &mut stm32f1xx_hal::rcc::APB2,
+) -> Player {
+    use stm32f1xx_hal::time::U32Ext;
+
+    let config = stm32f1xx_hal::serial::Config::default().baudrate(9600_u32.bps());
+
+    let mut serial =
+        stm32f1xx_hal::serial::Serial::usart1(serial_hw, (tx, rx), mapr, config, clocks, apb);
+
+    // Received bytes are handled in the interrupt
+    serial.listen(stm32f1xx_hal::serial::Event::Rxne);
+
+    player::DFPlayer::new(serial, CycleClock::new(clocks))
+}
+
+/// Trigger an interrupt on every change of the BUSY pin
+pub fn config_busy_interrupt(
+    busy: &mut PinBusy,
+    exti: &stm32f1xx_hal::device::EXTI,
+    afio: &mut stm32f1xx_hal::afio::Parts,
+) {
+    busy.make_interrupt_source(afio);
+    busy.trigger_on_edge(exti, Edge::RISING_FALLING);
+    busy.enable_interrupt(exti);
+}
+
+/// Setup the tag reader on SPI2
+#[allow(clippy::too_many_arguments)]
+pub fn tag_reader(
+    cs: PinCS,
+    clock: PinClock,
+    mosi: PinMosi,
+    miso: PinMiso,
+    spi_hw: stm32f1xx_hal::device::SPI2,
+    clocks: stm32f1xx_hal::rcc::Clocks,
+    apb: &mut stm32f1xx_hal::rcc::APB1,
+) -> Result<TagReader, tagreader::TagReaderError> {
+    use stm32f1xx_hal::time::U32Ext;
+
+    let spi_mode = mfrc522::MODE;
+
+    // A register access takes two bytes, the detection by the IRQ pin needs several of them
+    // every 50 ms. The MFRC522 supports up to 10 MHz.
+    let spi =
+        stm32f1xx_hal::spi::Spi::spi2(spi_hw, (clock, miso, mosi), spi_mode, 1.mhz(), clocks, apb);
+
+    tagreader::TagReader::new(spi, cs)
+}
+
+/// Trigger an interrupt when the MFRC522 detects a tag. The IRQ pin is low while the interrupt
+/// is pending.
+pub fn config_irq_interrupt(
+    irq: &mut PinIrq,
+    exti: &stm32f1xx_hal::device::EXTI,
+    afio: &mut stm32f1xx_hal::afio::Parts,
+) {
+    irq.make_interrupt_source(afio);
+    irq.trigger_on_edge(exti, Edge::FALLING);
+    irq.enable_interrupt(exti);
+}
@@ -14,16 +14,64 @@ use rtt_target::{rprintln, rtt_init_print};
 use core::convert::TryFrom;
 use rtic::app;
 use rtic::cyccnt::U32Ext;
+use rtic::Mutex;
 use stm32f1xx_hal::prelude::*;
 
+// Drivers that are shared with the host tests
+use music_box_drivers::{player, prompts, tagreader};
+
 // Mods that are used in the application
 mod app;
+mod board;
 mod buttons;
-mod player;
-mod tagreader;
+mod settings;
+mod tagmap;
 
 const CYCLES_10_MS: u32 = 64_000_000 / 100;
 
+/// Number of retries for commands that are not acknowledged by the DFPlayer. Every try waits
+/// up to 200 ms for the acknowledgement, this bounds the time `player_send` blocks.
+const PLAYER_ACK_RETRIES: u8 = 3;
+
+/// Chip on the DFPlayer module
+const PLAYER_CHIP: player::ChipVariant = player::ChipVariant::Yx5200;
+
+/// Interval of the detection by the IRQ pin, a placed tag is noticed after this time at most.
+/// The tag reader is powered down in between.
+const TAG_ARM_INTERVAL_MS: u32 = 100;
+/// Gain of the receiver of the tag reader, increase it if tags are only read close to the reader
+const TAG_ANTENNA_GAIN: tagreader::AntennaGain = tagreader::AntennaGain::Db33;
+/// Polls of the tag reader (every 500 ms) without a tag until it counts as removed
+const TAG_REMOVAL_POLLS: u8 = 2;
+
+/// Keys to authenticate MIFARE Classic tags. The key selected in the admin menu is tried
+/// first, then the others in this order. Add the keys of your own cards here.
+const TAG_KEYS: [tagreader::CardKey; 3] = [
+    tagreader::CardKey::DEFAULT,
+    // Key A of the MIFARE Application Directory sector
+    tagreader::CardKey::new(tagreader::KeyType::A, [0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5]),
+    // Key A of NFC Forum formatted sectors
+    tagreader::CardKey::new(tagreader::KeyType::A, [0xD3, 0xF7, 0xD3, 0xF7, 0xD3, 0xF7]),
+];
+
+/// Key B of protected cards. Protected cards can only be rewritten by boxes with this key.
+/// Set your own secret key to enable protecting cards.
+const TAG_ADMIN_KEY: Option<[u8; 6]> = None;
+
+/// Volume of the DFPlayer after startup
+const PLAYER_DEFAULT_VOLUME: u8 = 15;
+
+/// Duration of volume fades on play, pause and resume
+const FADE_DURATION_MS: u32 = 1500;
+/// Time between two volume steps of a fade
+const FADE_STEP_MS: u32 = 100;
+
+/// Interval of the sleep timer in seconds
+const SLEEP_TIMER_TICK_S: u16 = 10;
+
+/// Time without playback until the DFPlayer enters standby in seconds
+const PLAYER_STANDBY_S: u16 = 300;
+
 /// On board LED type alias
 type OnBoardLED =
     stm32f1xx_hal::gpio::gpioc::PC13<stm32f1xx_hal::gpio::Output<stm32f1xx_hal::gpio::PushPull>>;
@@ -31,6 +79,100 @@ type OnBoardLED =
 /// Queue for sending events to main app logic
 static EVENT_QUEUE: heapless::mpmc::Q8<app::Events> = heapless::mpmc::Q8::new();
 
+/// Send an event to the main app logic. If the queue is full the event is dropped, tasks must
+/// not panic because idle is busy.
+fn enqueue_event(event: app::Events) {
+    if let Err(event) = EVENT_QUEUE.enqueue(event) {
+        rprintln!("Event queue full, dropped {:?}", event);
+    }
+}
+
+/// Report failed DFPlayer commands to the main app logic
+fn check_player(result: Result<(), player::Error>) {
+    if let Err(error) = result {
+        rprintln!("DFPlayer command failed: {:?}", error);
+        if error == player::Error::NoAck {
+            EVENT_QUEUE.enqueue(app::Events::PlayerNotResponding).ok();
+        }
+    }
+}
+
+/// Use the settings for the DFPlayer and the tag reader
+fn apply_settings(
+    settings: &settings::Settings,
+    player: &mut board::Player,
+    tagreader: &mut board::TagReader,
+) {
+    player.configure(settings.equalizer, settings.device);
+    tagreader.set_card_block(settings.card_block);
+    tagreader.set_preferred_key(usize::from(settings.card_key));
+}
+
+/// Report the result of programming a card to the application
+fn card_written(written: Result<(), tagreader::WriteError>) {
+    if let Err(error) = written {
+        rprintln!("Writing the card failed: {:?}", error);
+    }
+    enqueue_event(app::Events::CardWritten(written.is_ok()));
+}
+
+/// Command that plays an announcement. While paused the current track is remembered to bring
+/// it back after the announcement, it starts again from its beginning.
+fn announce(
+    announcement: app::Announcement,
+    playing: bool,
+    playlist: &Option<app::Playlist>,
+    announcer: &mut app::Announcer,
+) -> player::Command {
+    if playing {
+        player::Command::Advert(announcement.track())
+    } else {
+        speak(&[announcement.track()], playing, playlist, announcer)
+            .unwrap_or(player::Command::PlayMp3(announcement.track()))
+    }
+}
+
+/// Command that starts a sequence of prompts from the `mp3` folder. The current track is
+/// remembered to bring it back after the sequence.
+fn speak(
+    prompts: &[u16],
+    playing: bool,
+    playlist: &Option<app::Playlist>,
+    announcer: &mut app::Announcer,
+) -> Option<player::Command> {
+    let interrupted = playlist
+        .as_ref()
+        .and_then(|list| list.current_folder_track())
+        .map(|(folder, track)| app::Interrupted {
+            folder,
+            track,
+            playing,
+        });
+    announcer
+        .start(prompts, interrupted)
+        .map(player::Command::PlayMp3)
+}
+
+/// Event for the main app logic from a DFPlayer response
+fn player_event(response: player::Response) -> Option<app::Events> {
+    use app::Events::*;
+    use player::Response;
+    match response {
+        Response::TrackFinished(track) => Some(TrackEnded(track)),
+        Response::MediaInserted(media) if media & player::MEDIA_SD != 0 => Some(SdInserted),
+        Response::MediaRemoved(media) if media & player::MEDIA_SD != 0 => Some(SdRemoved),
+        // The module has been restarted
+        Response::Online(media) if media & player::MEDIA_SD != 0 => Some(SdInserted),
+        Response::Online(_) => Some(NoSdCard),
+        Response::Error(error) => Some(PlayerError(error)),
+        Response::Ack => None,
+        other => {
+            rprintln!("Unhandled DFPlayer response: {:?}", other);
+            None
+        }
+    }
+}
+
 #[app(device=stm32f1xx_hal::device, monotonic=rtic::cyccnt::CYCCNT, peripherals=true)]
 const APP: () = {
     struct Resources {
@@ -43,12 +185,42 @@ const APP: () = {
             buttons::Button<buttons::PinBtnPlayPause>,
         ),
         /// RFID Tag reader
-        tagreader: tagreader::TagReader,
+        tagreader: board::TagReader,
+        /// IRQ pin of the tag reader if it is wired
+        tag_irq: Option<board::PinIrq>,
+        /// Cards of tags that can not be programmed
+        tag_map: tagmap::TagMap,
         /// DFPlayer
-        player: player::DFPlayer,
+        player: board::Player,
+        /// Commands waiting to be send to the DFPlayer
+        commands: player::CommandQueue,
+        /// BUSY pin of the DFPlayer if it is wired
+        busy: Option<board::PinBusy>,
+        /// Amplifier if its enable pin is wired
+        amp: Option<player::Amplifier<board::PinAmpEnable>>,
+        /// Flash for the settings
+        flash: stm32f1xx_hal::flash::Parts,
+        /// Running volume fade
+        #[init(None)]
+        fade: Option<app::Fade>,
+        /// Remaining ticks of the sleep timer, 0 if it is not running
+        #[init(0)]
+        sleep_timer: u16,
+        /// Remaining ticks until the DFPlayer enters standby, 0 while playing
+        #[init(0)]
+        standby_timer: u16,
+        /// Programming of the next tag instead of reading it
+        #[init(None)]
+        program: Option<app::Programming>,
+        /// Folder whose number of tracks player_send has to ask for
+        #[init(None)]
+        folder_query: Option<u8>,
+        /// The DFPlayer has no SD card, the LED blinks
+        #[init(false)]
+        sd_missing: bool,
     }
 
-    #[init(spawn=[check_for_tag])]
+    #[init(spawn=[check_for_tag, tag_arm, player_send])]
     fn init(mut cx: init::Context) -> init::LateResources {
         // Init the RTT Channel for logging
         rtt_init_print!();
@@ -109,7 +281,7 @@ const APP: () = {
         let spi_miso = gpiob.pb14.into_floating_input(&mut gpiob.crh);
         let spi_mosi = gpiob.pb15.into_alternate_push_pull(&mut gpiob.crh);
 
-        let tagreader = tagreader::TagReader::new(
+        let mut tagreader = board::tag_reader(
             spi_cs,
             spi_clock,
             spi_mosi,
@@ -117,14 +289,23 @@ const APP: () = {
             dp.SPI2,
             clocks,
             &mut rcc.apb1,
-        );
+        )
+        // Only the SPI peripheral can fail here, a MFRC522 that does not answer is reset later
+        .unwrap();
+        tagreader.set_removal_polls(TAG_REMOVAL_POLLS);
+        tagreader.set_keys(&TAG_KEYS);
+        tagreader.set_admin_key(TAG_ADMIN_KEY);
+        tagreader.set_antenna_gain(TAG_ANTENNA_GAIN);
+        EVENT_QUEUE
+            .enqueue(app::Events::ReaderSelfTest(tagreader.self_test()))
+            .unwrap();
 
         // Init the Dfplayer
         rprintln!("Setup DFPlayer");
         let serial_tx = gpioa.pa9.into_alternate_push_pull(&mut gpioa.crh);
         let serial_rx = gpioa.pa10.into_floating_input(&mut gpioa.crh);
 
-        let player = player::DFPlayer::new(
+        let mut player = board::player(
             dp.USART1,
             serial_tx,
             serial_rx,
@@ -132,49 +313,495 @@ const APP: () = {
             clocks,
             &mut rcc.apb2,
         );
+        player.set_ack_retries(Some(PLAYER_ACK_RETRIES));
+        player.set_chip_variant(PLAYER_CHIP);
+        let settings = settings::Settings::load(&mut flash);
+        apply_settings(&settings, &mut player, &mut tagreader);
+        let tag_map = tagmap::TagMap::load(&mut flash);
+
+        // Wait for the DFPlayer to boot
+        match player.startup() {
+            Ok(media) => {
+                rprintln!("DFPlayer online with media {:#04x}", media);
+                if !player.has_sd_card() {
+                    EVENT_QUEUE.enqueue(app::Events::NoSdCard).unwrap();
+                }
+            }
+            Err(error) => {
+                rprintln!("DFPlayer did not start: {:?}", error);
+                EVENT_QUEUE
+                    .enqueue(app::Events::PlayerNotResponding)
+                    .unwrap();
+            }
+        }
+
+        #[cfg(feature = "busy-pin")]
+        let busy = {
+            let mut busy = gpiob.pb5.into_floating_input(&mut gpiob.crl);
+            board::config_busy_interrupt(&mut busy, &dp.EXTI, &mut afio);
+            player.enable_busy_tracking();
+            Some(busy)
+        };
+        #[cfg(not(feature = "busy-pin"))]
+        let busy = None;
+
+        #[cfg(feature = "tag-irq")]
+        let tag_irq = {
+            let mut irq = gpioa.pa3.into_pull_up_input(&mut gpioa.crl);
+            board::config_irq_interrupt(&mut irq, &dp.EXTI, &mut afio);
+            Some(irq)
+        };
+        #[cfg(not(feature = "tag-irq"))]
+        let tag_irq = None;
+
+        #[cfg(feature = "amp-enable")]
+        let amp = Some(player::Amplifier::new(
+            gpiob.pb6.into_push_pull_output(&mut gpiob.crl),
+        ));
+        #[cfg(not(feature = "amp-enable"))]
+        let amp = None;
+
+        let mut commands = player::CommandQueue::new(PLAYER_DEFAULT_VOLUME);
+        commands
+            .push(player::Command::SetVolume(PLAYER_DEFAULT_VOLUME))
+            .unwrap();
 
         rprintln!("Setup done");
 
         // Spwan tasks
-        cx.spawn.check_for_tag().unwrap();
+        if tag_irq.is_some() {
+            cx.spawn.tag_arm().unwrap();
+        } else {
+            cx.spawn.check_for_tag().unwrap();
+        }
+        cx.spawn.player_send().unwrap();
 
         // Return late resources
         init::LateResources {
             led,
             buttons: (btn_up, btn_down, btn_playpause),
             tagreader,
+            tag_irq,
+            tag_map,
             player,
+            commands,
+            busy,
+            amp,
+            flash,
         }
     }
 
-    #[idle(resources=[led ])]
-    fn idle(_cx: idle::Context) -> ! {
+    #[idle(
+        resources=[
+            led,
+            player,
+            commands,
+            fade,
+            sleep_timer,
+            standby_timer,
+            sd_missing,
+            flash,
+            program,
+            folder_query,
+            tagreader,
+            tag_map
+        ],
+        spawn=[player_send, fade_step, sleep_timer_tick, standby_timer_tick, blink_error]
+    )]
+    fn idle(cx: idle::Context) -> ! {
         rprintln!("Entering Idle Loop");
+        let mut player = cx.resources.player;
+        let mut commands = cx.resources.commands;
+        let mut fade = cx.resources.fade;
+        let mut sleep_timer = cx.resources.sleep_timer;
+        let mut standby_timer = cx.resources.standby_timer;
+        let mut sd_missing = cx.resources.sd_missing;
+        let mut program = cx.resources.program;
+        let mut folder_query = cx.resources.folder_query;
+        let mut tagreader = cx.resources.tagreader;
+        let mut tag_map = cx.resources.tag_map;
+        let flash = cx.resources.flash;
+        let spawn = cx.spawn;
+        // Commands have been queued in this pass of the loop
+        let queued = core::cell::Cell::new(false);
+        // Queue a command for the DFPlayer
+        let mut send = |command: player::Command| {
+            if commands.lock(|commands| commands.push(command)).is_err() {
+                rprintln!("DFPlayer command queue full, dropped {:?}", command);
+            }
+            queued.set(true);
+            spawn.player_send().ok();
+        };
+        // Start a new fade, a running one is cancelled
+        let mut start_fade = |new_fade: Option<app::Fade>| {
+            fade.lock(|fade| *fade = new_fade);
+            spawn.fade_step().ok();
+        };
+        let mut announcer = app::Announcer::default();
+        let mut volume = PLAYER_DEFAULT_VOLUME;
+        let mut playing = false;
+        let mut playlist: Option<app::Playlist> = None;
+        let mut random = app::Random::new(cortex_m::peripheral::DWT::get_cycle_count());
+        let mut settings = settings::Settings::load(flash);
+        let mut admin: Option<app::AdminMenu> = None;
+        // Card of the playlist and if it has been removed from the box
+        let mut current_card: Option<app::Card> = None;
+        let mut card_removed = false;
+        // Mode of the card that waits for the number of tracks in its folder
+        let mut pending_mode: Option<app::Modus> = None;
+        // Nothing plays after the boot, so count down to standby right away. The first tick
+        // runs immediately.
+        standby_timer
+            .lock(|standby_timer| *standby_timer = PLAYER_STANDBY_S / SLEEP_TIMER_TICK_S + 1);
+        spawn.standby_timer_tick().ok();
         loop {
+            let was_playing = playing;
+            let mut admin_action = None;
             match EVENT_QUEUE.dequeue() {
                 Some(app::Events::NewTag(card)) => {
                     rprintln!("Event: New Tag detected -- {:?}", card);
                     // Check if it is a modifyer tag
                     if let Ok(modifyer) = app::Modifyer::try_from(card) {
                         rprintln!("Found Modifyer Card: {:#?}", modifyer);
+                        if modifyer == app::Modifyer::AdminMenu {
+                            admin = Some(app::AdminMenu::new(settings, TAG_KEYS.len() as u8));
+                        }
+                        if let app::Modifyer::SleepTimer(minutes) = modifyer {
+                            // The first tick runs immediately
+                            let ticks = u16::from(minutes) * (60 / SLEEP_TIMER_TICK_S) + 1;
+                            sleep_timer.lock(|sleep_timer| *sleep_timer = ticks);
+                            spawn.sleep_timer_tick().ok();
+                        }
+                        if let Some(announcement) = modifyer.announcement() {
+                            send(announce(announcement, playing, &playlist, &mut announcer));
+                        }
+                    } else if settings.stay_on_box
+                        && card_removed
+                        && current_card == Some(card)
+                        && playlist.is_some()
+                    {
+                        rprintln!("Card is back on the box");
+                        card_removed = false;
+                        if !playing {
+                            // Resume silent and fade in
+                            start_fade(None);
+                            send(player::Command::SetVolume(0));
+                            send(player::Command::Resume);
+                            start_fade(Some(app::Fade::new(
+                                0,
+                                volume,
+                                FADE_DURATION_MS,
+                                app::AfterFade::Nothing,
+                            )));
+                            playing = true;
+                        }
                     } else if let Ok(mode) = app::Modus::try_from(card) {
                         rprintln!("Found Normal Card: {:#?}", mode);
+                        current_card = Some(card);
+                        card_removed = false;
+                        announcer.cancel();
+                        // The playlist starts when player_send reports the number of tracks
+                        pending_mode = Some(mode);
+                        folder_query.lock(|folder_query| *folder_query = Some(mode.folder()));
+                        queued.set(true);
+                        spawn.player_send().ok();
                     } else {
                         rprintln!("Unknown Card Type");
+                        let announcement = app::Announcement::CardUnknown;
+                        send(announce(announcement, playing, &playlist, &mut announcer));
                     };
                 }
+                Some(app::Events::FolderTracks(folder, track_count)) => {
+                    rprintln!("Event: Folder {} has {} tracks", folder, track_count);
+                    // Answers for a card that has been replaced in the meantime are ignored
+                    if let Some(mode) = pending_mode.filter(|mode| mode.folder() == folder) {
+                        pending_mode = None;
+                        let list = app::Playlist::new(mode, track_count, &mut random);
+                        if let Some(track) = list.current() {
+                            // Start silent and fade in
+                            start_fade(None);
+                            send(player::Command::SetVolume(0));
+                            send(player::Command::PlayFolderTrack(list.folder(), track));
+                            start_fade(Some(app::Fade::new(
+                                0,
+                                volume,
+                                FADE_DURATION_MS,
+                                app::AfterFade::Nothing,
+                            )));
+                            playing = true;
+                        }
+                        playlist = Some(list);
+                    }
+                }
+                Some(app::Events::TagRemoved) => {
+                    rprintln!("Event: Tag removed");
+                    // Pause until the card is put back
+                    if settings.stay_on_box && playing && playlist.is_some() {
+                        start_fade(Some(app::Fade::new(
+                            volume,
+                            0,
+                            FADE_DURATION_MS,
+                            app::AfterFade::Pause(volume),
+                        )));
+                        playing = false;
+                        card_removed = true;
+                    }
+                }
+                Some(app::Events::ButtonPressedLong(button)) if admin.is_some() => {
+                    rprintln!("Event: Button {:?} Pressed Long in admin menu", button);
+                    admin_action = admin.as_mut().map(|menu| menu.button(button, true));
+                }
+                Some(app::Events::ButtonPressedShort(button)) if admin.is_some() => {
+                    rprintln!("Event: Button {:?} Pressed Short in admin menu", button);
+                    admin_action = admin.as_mut().map(|menu| menu.button(button, false));
+                }
                 Some(app::Events::ButtonPressedLong(button)) => match button {
-                    app::Button::Up => rprintln!("Event: Button Up Pressed Long"),
-                    app::Button::Down => rprintln!("Event: Button Down Pressed Long"),
-                    app::Button::PlayPause => rprintln!("Event: Button PlayPause Pressed Long"),
+                    app::Button::Up => {
+                        rprintln!("Event: Button Up Pressed Long");
+                        if let Some(list) = playlist.as_mut() {
+                            if let Some(track) = list.next() {
+                                send(player::Command::PlayFolderTrack(list.folder(), track));
+                                playing = true;
+                            }
+                        }
+                    }
+                    app::Button::Down => {
+                        rprintln!("Event: Button Down Pressed Long");
+                        if let Some(list) = playlist.as_mut() {
+                            if let Some(track) = list.previous() {
+                                send(player::Command::PlayFolderTrack(list.folder(), track));
+                                playing = true;
+                            }
+                        }
+                    }
+                    app::Button::PlayPause => {
+                        rprintln!("Event: Button PlayPause Pressed Long");
+                        // Tell folder and track that is playing
+                        if let Some((folder, track)) = playlist
+                            .as_ref()
+                            .and_then(|list| list.current_folder_track())
+                        {
+                            let mut prompts = app::labeled_number_prompts(
+                                app::Announcement::Folder,
+                                u16::from(folder),
+                            );
+                            prompts
+                                .extend_from_slice(&app::labeled_number_prompts(
+                                    app::Announcement::Track,
+                                    u16::from(track),
+                                ))
+                                .ok();
+                            if let Some(command) =
+                                speak(&prompts, playing, &playlist, &mut announcer)
+                            {
+                                send(command);
+                            }
+                        }
+                    }
                 },
                 Some(app::Events::ButtonPressedShort(button)) => match button {
-                    app::Button::Up => rprintln!("Event: Button Up Pressed Short"),
-                    app::Button::Down => rprintln!("Event: Button Down Pressed Short"),
-                    app::Button::PlayPause => rprintln!("Event: Button PlayPause Pressed Short"),
+                    app::Button::Up => {
+                        rprintln!("Event: Button Up Pressed Short");
+                        start_fade(None);
+                        volume = (volume + 1).min(player::MAX_VOLUME);
+                        send(player::Command::SetVolume(volume));
+                        if volume == player::MAX_VOLUME {
+                            let announcement = app::Announcement::VolumeMax;
+                            send(announce(announcement, playing, &playlist, &mut announcer));
+                        }
+                    }
+                    app::Button::Down => {
+                        rprintln!("Event: Button Down Pressed Short");
+                        start_fade(None);
+                        volume = volume.saturating_sub(1);
+                        send(player::Command::SetVolume(volume));
+                        if volume == 0 {
+                            let announcement = app::Announcement::VolumeMin;
+                            send(announce(announcement, playing, &playlist, &mut announcer));
+                        }
+                    }
+                    app::Button::PlayPause => {
+                        rprintln!("Event: Button PlayPause Pressed Short");
+                        if playing {
+                            // Fade out and pause afterwards
+                            start_fade(Some(app::Fade::new(
+                                volume,
+                                0,
+                                FADE_DURATION_MS,
+                                app::AfterFade::Pause(volume),
+                            )));
+                        } else {
+                            // Resume silent and fade in
+                            start_fade(None);
+                            send(player::Command::SetVolume(0));
+                            send(player::Command::Resume);
+                            start_fade(Some(app::Fade::new(
+                                0,
+                                volume,
+                                FADE_DURATION_MS,
+                                app::AfterFade::Nothing,
+                            )));
+                        }
+                        playing = !playing;
+                    }
                 },
+                Some(app::Events::TrackEnded(track)) => {
+                    rprintln!("Event: Track {} ended", track);
+                    if announcer.is_active() {
+                        if let Some(prompt) = announcer.next() {
+                            send(player::Command::PlayMp3(prompt));
+                        } else if let Some(interrupted) = announcer.end() {
+                            // Bring back the interrupted track
+                            send(player::Command::PlayFolderTrack(
+                                interrupted.folder,
+                                interrupted.track,
+                            ));
+                            if !interrupted.playing {
+                                send(player::Command::Pause);
+                            }
+                        }
+                    } else if let Some((folder, track)) = playlist
+                        .as_mut()
+                        .and_then(|list| list.next().map(|track| (list.folder(), track)))
+                    {
+                        send(player::Command::PlayFolderTrack(folder, track));
+                    } else {
+                        // Nothing left to resume
+                        playlist = None;
+                        playing = false;
+                    }
+                }
+                Some(app::Events::SleepTimerExpired) => {
+                    rprintln!("Event: Sleep timer expired");
+                    if playing {
+                        start_fade(Some(app::Fade::new(
+                            volume,
+                            0,
+                            FADE_DURATION_MS,
+                            app::AfterFade::Pause(volume),
+                        )));
+                        playing = false;
+                    }
+                }
+                Some(app::Events::SdInserted) => {
+                    rprintln!("Event: SD card inserted");
+                    sd_missing.lock(|sd_missing| *sd_missing = false);
+                }
+                Some(app::Events::SdRemoved) | Some(app::Events::NoSdCard) => {
+                    rprintln!("Event: No SD card");
+                    sd_missing.lock(|sd_missing| *sd_missing = true);
+                    spawn.blink_error().ok();
+                    announcer.cancel();
+                    playlist = None;
+                    playing = false;
+                }
+                Some(app::Events::PlayerError(error)) => {
+                    rprintln!("Event: DFPlayer reported error {:#04x}", error)
+                }
+                Some(app::Events::CardWritten(written)) => {
+                    rprintln!("Event: Card written -- {}", written);
+                    let announcement = if written {
+                        app::Announcement::CardWritten
+                    } else {
+                        app::Announcement::CardWriteFailed
+                    };
+                    send(announce(announcement, playing, &playlist, &mut announcer));
+                }
+                Some(app::Events::TagMapped(changed)) => {
+                    rprintln!("Event: Tag mapped -- {}", changed);
+                    let saved = changed
+                        && tag_map
+                            .lock(|tag_map| tag_map.clone())
+                            .save(flash)
+                            .map_err(|error| rprintln!("Could not save tag mapping: {:?}", error))
+                            .is_ok();
+                    let announcement = if saved {
+                        app::Announcement::Saved
+                    } else {
+                        app::Announcement::CardWriteFailed
+                    };
+                    send(announce(announcement, playing, &playlist, &mut announcer));
+                }
+                Some(app::Events::ReaderSelfTest(result)) => {
+                    rprintln!("Event: Tag reader self-test -- {:?}", result);
+                    if !result.is_ok() {
+                        let announcement = app::Announcement::ReaderDefect;
+                        send(announce(announcement, playing, &playlist, &mut announcer));
+                    }
+                }
+                Some(app::Events::PlayerNotResponding) => {
+                    rprintln!("Event: DFPlayer does not respond")
+                }
                 None => (),
             }
+
+            let announcement = match admin_action {
+                Some(app::AdminAction::Announce(announcement)) => Some(announcement),
+                Some(app::AdminAction::AnnounceNumber(label, number)) => {
+                    let prompts = app::labeled_number_prompts(label, number);
+                    if let Some(command) = speak(&prompts, playing, &playlist, &mut announcer) {
+                        send(command);
+                    }
+                    None
+                }
+                Some(app::AdminAction::Program(programming)) => {
+                    program.lock(|program| *program = Some(programming));
+                    Some(app::Announcement::PlaceCard)
+                }
+                Some(app::AdminAction::Preview(preview, announcement)) => {
+                    player.lock(|player| {
+                        tagreader.lock(|tagreader| apply_settings(&preview, player, tagreader))
+                    });
+                    Some(announcement)
+                }
+                Some(app::AdminAction::PreviewNumber(preview, label, number)) => {
+                    player.lock(|player| {
+                        tagreader.lock(|tagreader| apply_settings(&preview, player, tagreader))
+                    });
+                    let prompts = app::labeled_number_prompts(label, number);
+                    if let Some(command) = speak(&prompts, playing, &playlist, &mut announcer) {
+                        send(command);
+                    }
+                    None
+                }
+                Some(app::AdminAction::Save(changed)) => {
+                    settings = changed;
+                    if let Err(error) = settings.save(flash) {
+                        rprintln!("Could not save settings: {:?}", error);
+                    }
+                    admin = None;
+                    program.lock(|program| *program = None);
+                    Some(app::Announcement::Saved)
+                }
+                Some(app::AdminAction::Cancel(original)) => {
+                    player.lock(|player| {
+                        tagreader.lock(|tagreader| apply_settings(&original, player, tagreader))
+                    });
+                    admin = None;
+                    program.lock(|program| *program = None);
+                    Some(app::Announcement::MenuExit)
+                }
+                None => None,
+            };
+            if let Some(announcement) = announcement {
+                send(announce(announcement, playing, &playlist, &mut announcer));
+            }
+
+            // Count down to standby when the playback stops. Any command while nothing plays
+            // wakes the DFPlayer up, so the count down starts again after it.
+            let woken = queued.replace(false) && !playing;
+            if playing != was_playing || woken {
+                let ticks = if playing {
+                    0
+                } else {
+                    // The first tick runs immediately
+                    PLAYER_STANDBY_S / SLEEP_TIMER_TICK_S + 1
+                };
+                standby_timer.lock(|standby_timer| *standby_timer = ticks);
+                if !playing {
+                    spawn.standby_timer_tick().ok();
+                }
+            }
         }
     }
 
@@ -183,23 +810,305 @@ const APP: () = {
     //===============================================================================================
 
     // ==== Check if a tag is in the field ====
-    #[task(priority=4, resources=[tagreader, led], schedule = [check_for_tag])]
+    #[task(
+        priority=4,
+        resources=[tagreader, tag_irq, tag_map, led, sd_missing, program],
+        schedule = [check_for_tag]
+    )]
     fn check_for_tag(cx: check_for_tag::Context) {
         use embedded_hal::digital::v2::OutputPin;
-        if let Some(uid) = cx.resources.tagreader.check_for_new_tag() {
-            cx.resources.led.set_low().unwrap();
-            if let Some(card) = cx.resources.tagreader.read_card(uid) {
-                EVENT_QUEUE.enqueue(app::Events::NewTag(card)).unwrap();
+        // The LED shows the error pattern while the SD card is missing
+        let show_tag = !*cx.resources.sd_missing;
+        let errors = *cx.resources.tagreader.error_counters();
+        let change = cx.resources.tagreader.check_for_tag();
+        if let Some(tagreader::TagChange::New(uid)) = change {
+            if show_tag {
+                cx.resources.led.set_low().unwrap();
+            }
+            let tag_map = &mut cx.resources.tag_map;
+            match cx.resources.program.take() {
+                // The mapping is only changed here, idle stores it
+                Some(app::Programming::Map(card)) => {
+                    let mapped = tag_map.insert(tagmap::TagId::from(&uid), card);
+                    enqueue_event(app::Events::TagMapped(mapped));
+                }
+                Some(app::Programming::Unmap) => {
+                    let removed = tag_map.remove(&tagmap::TagId::from(&uid));
+                    enqueue_event(app::Events::TagMapped(removed));
+                }
+                // Program the card instead of playing it
+                Some(app::Programming::Write(card, protect)) => {
+                    card_written(cx.resources.tagreader.write_card(uid, card, protect))
+                }
+                Some(app::Programming::Unlock) => {
+                    card_written(cx.resources.tagreader.unlock_card(uid))
+                }
+                None => match cx.resources.tagreader.read_card(uid) {
+                    Ok(card) => enqueue_event(app::Events::NewTag(card)),
+                    // A broken MFRC522 says nothing about the tag
+                    Err(tagreader::TagReaderError::Spi) => rprintln!("Reading the card failed"),
+                    // Tags without a valid card may be mapped by their UID
+                    Err(error) => match tag_map.find(&tagmap::TagId::from(&uid)) {
+                        Some(card) => enqueue_event(app::Events::NewTag(card)),
+                        None => rprintln!("Reading the card failed: {:?}", error),
+                    },
+                },
             }
         } else {
-            cx.resources.led.set_high().unwrap();
+            if change == Some(tagreader::TagChange::Removed) {
+                enqueue_event(app::Events::TagRemoved);
+            }
+            if show_tag {
+                cx.resources.led.set_high().unwrap();
+            }
+        }
+
+        // A new failure logs the counters of all kinds
+        let counters = cx.resources.tagreader.error_counters();
+        if *counters != errors {
+            rprintln!("Tag reader errors: {:?}", counters);
+        }
+
+        // With the IRQ pin the polling is only needed to detect the removal of the tag
+        if cx.resources.tag_irq.is_none() || cx.resources.tagreader.has_tag() {
+            cx.schedule
+                .check_for_tag(cx.scheduled + (CYCLES_10_MS * 50).cycles())
+                .ok();
         }
+    }
 
+    // ==== Start the detection of a tag by the IRQ pin ====
+    #[task(priority=4, resources=[tagreader], schedule=[tag_arm])]
+    fn tag_arm(cx: tag_arm::Context) {
+        // While a tag is in the field check_for_tag polls until it is removed
+        if !cx.resources.tagreader.has_tag() {
+            cx.resources.tagreader.arm_detection();
+        }
         cx.schedule
-            .check_for_tag(cx.scheduled + (CYCLES_10_MS * 50).cycles())
+            .tag_arm(cx.scheduled + (CYCLES_10_MS * TAG_ARM_INTERVAL_MS / 10).cycles())
             .unwrap();
     }
 
+    // ==== IRQ pin of the tag reader, a tag answered or the timer expired ====
+    #[task(binds=EXTI3, priority=4, resources=[tag_irq, tagreader], spawn=[check_for_tag])]
+    fn tag_irq(cx: tag_irq::Context) {
+        use stm32f1xx_hal::gpio::ExtiPin;
+        if let Some(irq) = cx.resources.tag_irq.as_mut() {
+            irq.clear_interrupt_pending_bit();
+            if cx.resources.tagreader.disarm_detection() {
+                // Already running if the polling for the removal is active
+                cx.spawn.check_for_tag().ok();
+            } else {
+                // No tag, the reader sleeps until the next detection
+                cx.resources.tagreader.power_down();
+            }
+        }
+    }
+
+    //===============================================================================================
+    //==== Handling of the DFPlayer =====
+    //===============================================================================================
+
+    // ==== Receive notifications from the DFPlayer ====
+    #[task(binds=USART1, priority=3, resources=[player, amp], spawn=[player_send])]
+    fn player_rx(cx: player_rx::Context) {
+        while let Some(response) = cx.resources.player.poll() {
+            if let Some(event) = player_event(response) {
+                if let (app::Events::TrackEnded(_), Some(amp)) = (&event, cx.resources.amp.as_mut())
+                {
+                    amp.mute();
+                }
+                EVENT_QUEUE.enqueue(event).ok();
+            }
+        }
+        if cx.resources.player.config_pending() {
+            cx.spawn.player_send().ok();
+        }
+    }
+
+    // ==== BUSY pin of the DFPlayer changed ====
+    #[task(binds=EXTI9_5, priority=3, resources=[busy, player, amp])]
+    fn player_busy(cx: player_busy::Context) {
+        use embedded_hal::digital::v2::InputPin;
+        use stm32f1xx_hal::gpio::ExtiPin;
+        if let Some(busy) = cx.resources.busy.as_mut() {
+            busy.clear_interrupt_pending_bit();
+            let busy_high = busy.is_high().unwrap();
+            if let Some(event) = cx
+                .resources
+                .player
+                .busy_changed(busy_high)
+                .and_then(player_event)
+            {
+                if let (app::Events::TrackEnded(_), Some(amp)) = (&event, cx.resources.amp.as_mut())
+                {
+                    amp.mute();
+                }
+                EVENT_QUEUE.enqueue(event).ok();
+            }
+        }
+    }
+
+    // ==== Send queued commands to the DFPlayer ====
+    // Every run sends a single command and waits for its answer. A silent DFPlayer blocks idle and
+    // the tasks up to priority 3 for (PLAYER_ACK_RETRIES + 1) * 200 ms = 800 ms at most, the
+    // buttons and the tag reader run at higher priorities.
+    #[task(
+        priority=3,
+        resources=[player, commands, amp, folder_query],
+        schedule=[player_send]
+    )]
+    fn player_send(cx: player_send::Context) {
+        use rtic::cyccnt::Instant;
+        let player = cx.resources.player;
+        let commands = cx.resources.commands;
+        let amp = cx.resources.amp;
+        let folder_query = cx.resources.folder_query;
+
+        // Wait until the DFPlayer is ready for the next command
+        if let Some(ready_at) = player.ready_at() {
+            if Instant::now() < ready_at {
+                cx.schedule.player_send(ready_at).ok();
+                return;
+            }
+        }
+
+        let wake = player.is_sleeping()
+            && (folder_query.is_some()
+                || commands
+                    .peek()
+                    .map_or(false, |command| *command != player::Command::Standby));
+        if wake {
+            check_player(player.wake());
+        } else if player.config_pending() && !player.is_sleeping() {
+            // The configuration is lost after a reset or a media change
+            check_player(player.apply_config());
+        } else if let Some(folder) = folder_query.take() {
+            let track_count = player.folder_track_count(folder).unwrap_or_else(|error| {
+                rprintln!("Could not read number of tracks: {:?}", error);
+                0
+            });
+            enqueue_event(app::Events::FolderTracks(folder, track_count));
+        } else if let Some(command) = commands.pop() {
+            // Mute the amplifier while the playback starts or stops to avoid pops
+            if let Some(amp) = amp.as_mut() {
+                if command.stops_playback() {
+                    amp.mute();
+                }
+            }
+            check_player(player.send(command));
+            if let Some(amp) = amp.as_mut() {
+                if command.starts_playback() {
+                    amp.unmute();
+                }
+            }
+        } else {
+            return;
+        }
+        // Notifications received while waiting for an answer are kept by the driver
+        rtic::pend(stm32f1xx_hal::device::Interrupt::USART1);
+
+        let config_pending = player.config_pending() && !player.is_sleeping();
+        if !commands.is_empty() || folder_query.is_some() || config_pending {
+            let ready_at = player.ready_at().unwrap_or_else(Instant::now);
+            cx.schedule.player_send(ready_at).ok();
+        }
+    }
+
+    // ==== Step the volume of a fade ====
+    #[task(priority=2, resources=[fade, commands], spawn=[player_send], schedule=[fade_step])]
+    fn fade_step(cx: fade_step::Context) {
+        let mut commands = cx.resources.commands;
+        let fade = cx.resources.fade;
+
+        if let Some(running) = fade.as_mut() {
+            let volume = running.step(FADE_STEP_MS);
+            commands.lock(|commands| commands.push(player::Command::SetVolume(volume)).ok());
+
+            if running.is_done() {
+                if let app::AfterFade::Pause(restore) = running.after() {
+                    commands.lock(|commands| {
+                        commands.push(player::Command::Pause).ok();
+                        commands.push(player::Command::SetVolume(restore)).ok();
+                    });
+                }
+                *fade = None;
+            } else {
+                cx.schedule
+                    .fade_step(cx.scheduled + (CYCLES_10_MS * FADE_STEP_MS / 10).cycles())
+                    .ok();
+            }
+            cx.spawn.player_send().ok();
+        }
+    }
+
+    // ==== Blink the LED while the SD card is missing ====
+    #[task(priority=4, resources=[led, sd_missing], schedule=[blink_error])]
+    fn blink_error(cx: blink_error::Context) {
+        use embedded_hal::digital::v2::{OutputPin, ToggleableOutputPin};
+        if *cx.resources.sd_missing {
+            cx.resources.led.toggle().unwrap();
+            cx.schedule
+                .blink_error(cx.scheduled + (CYCLES_10_MS * 25).cycles())
+                .ok();
+        } else {
+            cx.resources.led.set_high().unwrap();
+        }
+    }
+
+    //===============================================================================================
+    //==== Sleep Timer =====
+    //===============================================================================================
+
+    // ==== Count down the sleep timer ====
+    #[task(priority=1, resources=[sleep_timer], schedule=[sleep_timer_tick])]
+    fn sleep_timer_tick(cx: sleep_timer_tick::Context) {
+        let sleep_timer = cx.resources.sleep_timer;
+        if *sleep_timer == 0 {
+            // Timer has been stopped
+            return;
+        }
+
+        *sleep_timer -= 1;
+        if *sleep_timer == 0 {
+            EVENT_QUEUE.enqueue(app::Events::SleepTimerExpired).ok();
+        } else {
+            cx.schedule
+                .sleep_timer_tick(
+                    cx.scheduled + (CYCLES_10_MS * 100 * u32::from(SLEEP_TIMER_TICK_S)).cycles(),
+                )
+                .ok();
+        }
+    }
+
+    // ==== Count down to the standby of the DFPlayer ====
+    #[task(
+        priority=1,
+        resources=[standby_timer, commands],
+        spawn=[player_send],
+        schedule=[standby_timer_tick]
+    )]
+    fn standby_timer_tick(cx: standby_timer_tick::Context) {
+        let standby_timer = cx.resources.standby_timer;
+        let mut commands = cx.resources.commands;
+        if *standby_timer == 0 {
+            // Playback started again
+            return;
+        }
+
+        *standby_timer -= 1;
+        if *standby_timer == 0 {
+            commands.lock(|commands| commands.push(player::Command::Standby).ok());
+            cx.spawn.player_send().ok();
+        } else {
+            cx.schedule
+                .standby_timer_tick(
+                    cx.scheduled + (CYCLES_10_MS * 100 * u32::from(SLEEP_TIMER_TICK_S)).cycles(),
+                )
+                .ok();
+        }
+    }
+
     //===============================================================================================
     //==== Handling of the Buttons =====
     //===============================================================================================
@@ -246,7 +1155,7 @@ const APP: () = {
         use app::{Button::*, Events::*};
         // Check Iteration
         if iteration >= 100 {
-            EVENT_QUEUE.enqueue(ButtonPressedLong(btn)).unwrap();
+            enqueue_event(ButtonPressedLong(btn));
             // Schedule btn enable
             cx.schedule
                 .btn_enable(cx.scheduled + CYCLES_10_MS.cycles(), btn)
@@ -264,7 +1173,7 @@ const APP: () = {
                     .unwrap();
             } else {
                 // Emit Event
-                EVENT_QUEUE.enqueue(ButtonPressedShort(btn)).unwrap();
+                enqueue_event(ButtonPressedShort(btn));
                 // Schedule btn enable
                 cx.schedule
                     .btn_enable(cx.scheduled + CYCLES_10_MS.cycles(), btn)
@@ -296,9 +1205,12 @@ const APP: () = {
         }
     }
 
+    // One free interrupt for every priority of the software tasks (1 to 5)
     extern "C" {
         fn TIM2();
         fn TIM3();
         fn TIM4();
+        fn USART2();
+        fn USART3();
     }
 };
@@ -0,0 +1,107 @@
+use crate::player::{Equalizer, PlaybackDevice};
+use crate::tagreader;
+use core::convert::TryFrom;
+use stm32f1xx_hal::flash::{self, FlashSize, SectorSize};
+
+/// Offset of the settings page in the flash. The last page is reserved in `memory.x`.
+const SETTINGS_OFFSET: u32 = 63 * 1024;
+/// Size of the stored settings
+const SETTINGS_SIZE: usize = 12;
+/// Marks a page with valid settings
+const SETTINGS_MAGIC: u32 = 0x5E77_1265;
+/// Increase when the layout changes
+const SETTINGS_VERSION: u8 = 3;
+
+/// Settings that are changed in the admin menu and survive a power cycle
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Settings {
+    pub equalizer: Equalizer,
+    pub device: PlaybackDevice,
+    /// Removing the card pauses the playback, putting it back resumes it
+    pub stay_on_box: bool,
+    /// Block with the card data on MIFARE Classic tags
+    pub card_block: u8,
+    /// Index of the key that is tried first to authenticate MIFARE Classic tags
+    pub card_key: u8,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            equalizer: Equalizer::Normal,
+            device: PlaybackDevice::SdCard,
+            stay_on_box: false,
+            card_block: tagreader::DEFAULT_CARD_BLOCK,
+            card_key: 0,
+        }
+    }
+}
+
+impl Settings {
+    /// Read the settings from the flash, the defaults are used if nothing valid is stored
+    pub fn load(flash: &mut flash::Parts) -> Self {
+        let writer = flash.writer(SectorSize::Sz1K, FlashSize::Sz64K);
+        writer
+            .read(SETTINGS_OFFSET, SETTINGS_SIZE)
+            .ok()
+            .and_then(|data| {
+                let mut bytes = [0; SETTINGS_SIZE];
+                bytes.copy_from_slice(data);
+                Self::decode(&bytes)
+            })
+            .unwrap_or_default()
+    }
+
+    /// Store the settings in the flash
+    pub fn save(&self, flash: &mut flash::Parts) -> Result<(), flash::Error> {
+        let mut writer = flash.writer(SectorSize::Sz1K, FlashSize::Sz64K);
+        writer.erase(SETTINGS_OFFSET, 1024)?;
+        writer.write(SETTINGS_OFFSET, &self.encode())
+    }
+
+    fn encode(&self) -> [u8; SETTINGS_SIZE] {
+        let magic = SETTINGS_MAGIC.to_be_bytes();
+        let mut bytes = [
+            magic[0],
+            magic[1],
+            magic[2],
+            magic[3],
+            SETTINGS_VERSION,
+            self.equalizer as u8,
+            self.device as u8,
+            self.stay_on_box as u8,
+            self.card_block,
+            self.card_key,
+            0,
+            0,
+        ];
+        bytes[SETTINGS_SIZE - 1] = checksum(&bytes[..SETTINGS_SIZE - 1]);
+        bytes
+    }
+
+    fn decode(bytes: &[u8; SETTINGS_SIZE]) -> Option<Self> {
+        let magic = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        if magic != SETTINGS_MAGIC
+            || bytes[4] != SETTINGS_VERSION
+            || bytes[SETTINGS_SIZE - 1] != checksum(&bytes[..SETTINGS_SIZE - 1])
+            || !tagreader::is_data_block(bytes[8])
+        {
+            return None;
+        }
+        Some(Self {
+            equalizer: Equalizer::try_from(bytes[5]).ok()?,
+            device: PlaybackDevice::try_from(bytes[6]).ok()?,
+            stay_on_box: bytes[7] != 0,
+            card_block: bytes[8],
+            card_key: bytes[9],
+        })
+    }
+}
+
+/// Detect corrupted data in the flash
+pub fn checksum<'a>(bytes: impl IntoIterator<Item = &'a u8>) -> u8 {
+    bytes
+        .into_iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+        ^ 0xFF
+}
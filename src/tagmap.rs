@@ -0,0 +1,150 @@
+use crate::app::Card;
+use core::convert::TryFrom;
+use stm32f1xx_hal::flash::{self, FlashSize, SectorSize};
+use typenum::Unsigned;
+
+/// Offset of the tag mapping page in the flash, the page before the settings
+const TAG_MAP_OFFSET: u32 = 62 * 1024;
+/// Marks a page with a valid tag mapping
+const TAG_MAP_MAGIC: u32 = 0x7A63_0A9B;
+/// Increase when the layout changes
+const TAG_MAP_VERSION: u8 = 1;
+/// Magic, version, number of entries and checksum
+const HEADER_SIZE: usize = 8;
+/// Length and bytes of the UID followed by the card data
+const ENTRY_SIZE: usize = 28;
+/// Longest UID of ISO14443A tags (triple size)
+const MAX_UID_SIZE: usize = 10;
+/// Most tags that can be mapped
+type MaxTags = heapless::consts::U24;
+const MAX_TAGS: usize = MaxTags::USIZE;
+const TAG_MAP_SIZE: usize = HEADER_SIZE + MAX_TAGS * ENTRY_SIZE;
+
+/// Checksum over the header and the entries, the checksum byte itself is left out
+fn checksum(bytes: &[u8]) -> u8 {
+    crate::settings::checksum(bytes[..HEADER_SIZE - 1].iter().chain(&bytes[HEADER_SIZE..]))
+}
+
+/// UID of a tag as it is stored in the flash
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TagId {
+    len: u8,
+    bytes: [u8; MAX_UID_SIZE],
+}
+
+impl From<&mfrc522::Uid> for TagId {
+    fn from(uid: &mfrc522::Uid) -> Self {
+        let uid = uid.as_bytes();
+        let len = uid.len().min(MAX_UID_SIZE);
+        let mut bytes = [0; MAX_UID_SIZE];
+        bytes[..len].copy_from_slice(&uid[..len]);
+        Self {
+            len: len as u8,
+            bytes,
+        }
+    }
+}
+
+/// Cards for tags that can not be programmed, e.g. read-only tags or toy figurines.
+/// The tags are recognized by their UID.
+#[derive(Debug, Clone, Default)]
+pub struct TagMap {
+    entries: heapless::Vec<(TagId, Card), MaxTags>,
+}
+
+impl TagMap {
+    /// Read the mapping from the flash, it is empty if nothing valid is stored
+    pub fn load(flash: &mut flash::Parts) -> Self {
+        let writer = flash.writer(SectorSize::Sz1K, FlashSize::Sz64K);
+        writer
+            .read(TAG_MAP_OFFSET, TAG_MAP_SIZE)
+            .ok()
+            .and_then(Self::decode)
+            .unwrap_or_default()
+    }
+
+    /// Store the mapping in the flash
+    pub fn save(&self, flash: &mut flash::Parts) -> Result<(), flash::Error> {
+        let mut writer = flash.writer(SectorSize::Sz1K, FlashSize::Sz64K);
+        writer.erase(TAG_MAP_OFFSET, 1024)?;
+        writer.write(TAG_MAP_OFFSET, &self.encode())
+    }
+
+    /// Card of the tag with the given UID
+    pub fn find(&self, id: &TagId) -> Option<Card> {
+        self.entries
+            .iter()
+            .find(|(entry, _)| entry == id)
+            .map(|(_, card)| *card)
+    }
+
+    /// Map the tag to the card, a previous card of the tag is replaced.
+    /// Returns false if the table is full.
+    pub fn insert(&mut self, id: TagId, card: Card) -> bool {
+        match self.entries.iter_mut().find(|(entry, _)| *entry == id) {
+            Some(entry) => {
+                entry.1 = card;
+                true
+            }
+            None => self.entries.push((id, card)).is_ok(),
+        }
+    }
+
+    /// Forget the tag. Returns false if it was not mapped.
+    pub fn remove(&mut self, id: &TagId) -> bool {
+        match self.entries.iter().position(|(entry, _)| entry == id) {
+            Some(index) => {
+                self.entries.swap_remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn encode(&self) -> [u8; TAG_MAP_SIZE] {
+        let mut bytes = [0; TAG_MAP_SIZE];
+        bytes[..4].copy_from_slice(&TAG_MAP_MAGIC.to_be_bytes());
+        bytes[4] = TAG_MAP_VERSION;
+        bytes[5] = self.entries.len() as u8;
+        for ((id, card), entry) in self
+            .entries
+            .iter()
+            .zip(bytes[HEADER_SIZE..].chunks_mut(ENTRY_SIZE))
+        {
+            entry[0] = id.len;
+            entry[1..=MAX_UID_SIZE].copy_from_slice(&id.bytes);
+            entry[ENTRY_SIZE - 16..].copy_from_slice(&<[u8; 16]>::from(*card));
+        }
+        bytes[HEADER_SIZE - 1] = checksum(&bytes);
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let magic = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let count = usize::from(bytes[5]);
+        if magic != TAG_MAP_MAGIC
+            || bytes[4] != TAG_MAP_VERSION
+            || count > MAX_TAGS
+            || bytes[HEADER_SIZE - 1] != checksum(bytes)
+        {
+            return None;
+        }
+        let mut map = Self::default();
+        for entry in bytes[HEADER_SIZE..].chunks(ENTRY_SIZE).take(count) {
+            let len = entry[0];
+            if usize::from(len) > MAX_UID_SIZE {
+                return None;
+            }
+            let mut id = TagId {
+                len,
+                bytes: [0; MAX_UID_SIZE],
+            };
+            id.bytes.copy_from_slice(&entry[1..=MAX_UID_SIZE]);
+            let mut data = [0; 16];
+            data.copy_from_slice(&entry[ENTRY_SIZE - 16..]);
+            let card = Card::try_from(data).ok()?;
+            map.entries.push((id, card)).ok()?;
+        }
+        Some(map)
+    }
+}